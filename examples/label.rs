@@ -9,7 +9,7 @@ fn main() -> Result<()> {
         .date_naive()
         .format("%m/%y")
         .to_string();
-    let label = generate_label(name, "XXXX", &date);
+    let label = generate_label(name, "XXXX", &date, None)?;
     label
         .save_with_format(path, image::ImageFormat::Png)
         .unwrap();
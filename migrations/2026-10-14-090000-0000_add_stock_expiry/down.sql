@@ -0,0 +1 @@
+alter table stock drop column expiry_dt;
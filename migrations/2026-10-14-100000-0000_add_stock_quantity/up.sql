@@ -0,0 +1 @@
+alter table stock add column quantity int not null default 1 check (quantity > 0);
@@ -0,0 +1 @@
+alter table items add column min_stock int;
@@ -0,0 +1 @@
+drop table off_cache;
@@ -0,0 +1,5 @@
+create table off_cache (
+    ean varchar primary key,
+    name varchar,
+    fetched_dt timestamptz not null default now()
+);
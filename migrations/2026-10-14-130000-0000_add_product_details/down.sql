@@ -0,0 +1,2 @@
+alter table items drop column brand, drop column quantity, drop column category;
+alter table off_cache drop column brand, drop column quantity, drop column category;
@@ -0,0 +1,2 @@
+alter table items add column brand varchar, add column quantity varchar, add column category varchar;
+alter table off_cache add column brand varchar, add column quantity varchar, add column category varchar;
@@ -0,0 +1,2 @@
+drop table stock_log;
+drop type stock_action;
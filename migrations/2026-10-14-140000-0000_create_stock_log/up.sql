@@ -0,0 +1,10 @@
+create type stock_action as enum ('add', 'remove', 'open', 'finish');
+
+create table stock_log (
+    id serial primary key,
+    stock_id int not null references stock(id) on delete cascade,
+    action stock_action not null,
+    occurred_dt timestamptz not null default now()
+);
+
+create index on stock_log (stock_id);
@@ -0,0 +1,6 @@
+drop index items_lower_idx;
+create unique index items_lower_idx on items (lower(name));
+drop index items_ean_key;
+alter table items add constraint items_ean_key unique (ean);
+
+alter table items drop column deleted_dt;
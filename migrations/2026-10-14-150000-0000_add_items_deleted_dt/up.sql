@@ -0,0 +1,7 @@
+alter table items add column deleted_dt timestamptz;
+
+-- deleted items must not block registering their EAN or name again
+alter table items drop constraint items_ean_key;
+create unique index items_ean_key on items (ean) where deleted_dt is null;
+drop index items_lower_idx;
+create unique index items_lower_idx on items (lower(name)) where deleted_dt is null;
@@ -0,0 +1 @@
+drop table shopping_list;
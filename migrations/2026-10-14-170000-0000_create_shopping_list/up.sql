@@ -0,0 +1,8 @@
+create table shopping_list (
+    id serial primary key,
+    item_id int not null references items(id) on delete cascade,
+    added_dt timestamptz not null default now(),
+    removed_dt timestamptz
+);
+
+create unique index on shopping_list (item_id) where removed_dt is null;
@@ -0,0 +1 @@
+alter table items drop column open_shelf_days;
@@ -0,0 +1 @@
+alter table items add column open_shelf_days int;
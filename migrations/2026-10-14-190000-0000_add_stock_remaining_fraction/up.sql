@@ -0,0 +1 @@
+alter table stock add column remaining_fraction real check (remaining_fraction between 0 and 1);
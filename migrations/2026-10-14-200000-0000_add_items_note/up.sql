@@ -0,0 +1 @@
+alter table items add column note text;
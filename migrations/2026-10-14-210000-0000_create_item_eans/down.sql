@@ -0,0 +1,13 @@
+create table aliases (
+    ean varchar primary key,
+    alias_for varchar not null
+);
+
+-- EANs of items without an EAN of their own cannot be expressed as aliases
+insert into aliases (ean, alias_for)
+select item_eans.ean, items.ean
+from item_eans
+join items on items.id = item_eans.item_id
+where items.ean is not null;
+
+drop table item_eans;
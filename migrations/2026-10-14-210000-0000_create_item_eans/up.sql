@@ -0,0 +1,14 @@
+create table item_eans (
+    ean varchar primary key,
+    item_id int not null references items(id) on delete cascade
+);
+create index on item_eans (item_id);
+
+-- an alias belongs to the item with the EAN it points to, preferring one that is not deleted
+insert into item_eans (ean, item_id)
+select distinct on (aliases.ean) aliases.ean, items.id
+from aliases
+join items on items.ean = aliases.alias_for
+order by aliases.ean, items.deleted_dt is not null, items.id desc;
+
+drop table aliases;
@@ -0,0 +1,130 @@
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use crate::config::ScanCodes;
+
+/// Keep track of what is in the larder with a barcode scanner.
+#[derive(Debug, Parser)]
+#[command(version = concat!(env!("CARGO_PKG_VERSION"), " (", env!("LARDER_GIT_HASH"), ")"))]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub run: RunArgs,
+    /// Only pretend to print labels
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Process scans (the default)
+    Run(RunArgs),
+    /// Export all data to a file
+    Export {
+        path: PathBuf,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+        /// Skip stock that has been removed (CSV only)
+        #[arg(long)]
+        active_only: bool,
+    },
+    /// Import a JSON export
+    Import {
+        path: PathBuf,
+        /// Merge into the existing data instead of restoring into an empty database
+        #[arg(long)]
+        merge: bool,
+    },
+    /// Show the shopping list
+    ShoppingList,
+    /// Show how fast items are used up
+    Stats {
+        /// How many weeks back to look at
+        #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..))]
+        weeks: u32,
+    },
+    /// Print labels that stand for any unit of an item
+    PrintItemLabels {
+        name: String,
+        #[arg(long, default_value_t = 1)]
+        count: u8,
+    },
+    /// Render a label to a PNG file instead of printing it
+    PreviewLabel {
+        name: String,
+        /// The date shown on the label, the current month by default
+        date: Option<String>,
+        #[arg(default_value = "label-preview.png")]
+        output: PathBuf,
+        #[arg(long)]
+        ean: Option<String>,
+    },
+    /// Check that the database and the printer are reachable
+    Healthcheck,
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// The input devices to read scans from, detected automatically if none are given
+    #[arg(long = "device", short, value_delimiter = ',')]
+    pub devices: Vec<PathBuf>,
+    /// Same as --device
+    #[arg(value_delimiter = ',', hide = true)]
+    pub device_args: Vec<PathBuf>,
+    /// Reset the scan op after this many seconds without scans
+    #[arg(long, value_name = "SECS")]
+    pub idle_timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl Cli {
+    /// Parse the command line. The scan codes are configurable, so the help is built at runtime.
+    pub fn parse_with_codes(codes: &ScanCodes) -> Self {
+        let command = Cli::command().after_help(scan_codes_help(codes));
+        Cli::from_arg_matches(&command.get_matches()).unwrap_or_else(|err| err.exit())
+    }
+}
+
+fn scan_codes_help(codes: &ScanCodes) -> String {
+    let mut help = String::from("Scan ops (apply to all following scans):\n");
+    for (code, desc) in [
+        (&codes.none, "show item info"),
+        (&codes.register, "register item"),
+        (&codes.add, "add to stock"),
+        (&codes.remove, "remove from stock"),
+        (&codes.open, "open"),
+        (&codes.finish, "finish an opened unit"),
+        (&codes.min_stock, "set minimum stock"),
+        (&codes.open_shelf_days, "set how long it keeps once opened"),
+        (&codes.remaining, "set how much of an opened unit is left"),
+        (&codes.rename, "rename item"),
+        (&codes.note, "set note"),
+        (&codes.reprint, "reprint label"),
+        (&codes.delete, "delete item"),
+        (&codes.move_location, "move to another location"),
+        (&codes.set_count, "set stock count"),
+        (&codes.count, "show stock count"),
+    ] {
+        writeln!(help, "  {code}  {desc}").expect("Writing to string should never fail");
+    }
+    help.push_str("\nActions:\n");
+    for (code, desc) in [
+        (&codes.create_custom, "create custom item"),
+        (&codes.list_expiring, "list expiring stock"),
+        (&codes.shopping_list, "print shopping list"),
+        (&codes.merge, "merge items"),
+        (&codes.remove_alias, "remove alias"),
+        (&codes.undo, "undo last change"),
+    ] {
+        writeln!(help, "  {code}  {desc}").expect("Writing to string should never fail");
+    }
+    help
+}
@@ -0,0 +1,301 @@
+//! The payload of the 2D codes printed on labels.
+//!
+//! A stock code identifies a single stock entry and looks like `~1:12|34~`: the format
+//! version, the item id and the stock id. Labels printed before the version was added
+//! contain `~12|34~`, which is still accepted. Codes are wrapped in `~` so they can never be
+//! mistaken for an EAN.
+//!
+//! An item code like `~1:12~` stands for any unit of an item instead, so it can be printed
+//! before the stock exists.
+//!
+//! Some products also carry GS1 codes with application identifiers (AIs), which are parsed
+//! here as well.
+
+use chrono::{Months, NaiveDate};
+
+/// The version of the stock code format written by `encode_stock_code`.
+pub const STOCK_CODE_VERSION: u32 = 1;
+
+pub fn encode_stock_code(item_id: i32, stock_id: i32) -> String {
+    format!("~{STOCK_CODE_VERSION}:{item_id}|{stock_id}~")
+}
+
+pub fn encode_item_code(item_id: i32) -> String {
+    format!("~{STOCK_CODE_VERSION}:{item_id}~")
+}
+
+/// Get the item and stock ids from a stock code. Anything else, including codes with
+/// trailing characters or an unknown version, is rejected.
+pub fn decode_stock_code(code: &str) -> Option<(i32, i32)> {
+    let (item_id, stock_id) = code_payload(code)?.split_once('|')?;
+    Some((parse_number(item_id)?, parse_number(stock_id)?))
+}
+
+/// Get the item id from an item code, which is as strict as `decode_stock_code`.
+pub fn decode_item_code(code: &str) -> Option<i32> {
+    parse_number(code_payload(code)?)
+}
+
+/// Get the ids from a code, without the `~` and the version.
+fn code_payload(code: &str) -> Option<&str> {
+    let inner = code.strip_prefix('~')?.strip_suffix('~')?;
+    match inner.split_once(':') {
+        Some((version, ids)) if parse_number::<u32>(version)? == STOCK_CODE_VERSION => Some(ids),
+        Some(_) => None,
+        // labels from before the format was versioned
+        None => Some(inner),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(s: &str) -> Option<T> {
+    // `parse` would also accept a sign
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// The data from a GS1 code that is relevant for the stock.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Gs1Data {
+    /// The GTIN, as an EAN-13 if it fits into one.
+    pub ean: String,
+    /// The expiry (AI 17) or best-before (AI 15) date.
+    pub expiry: Option<NaiveDate>,
+}
+
+/// Parse a GS1 code containing a GTIN (AI 01), either in its raw form as sent by the scanner
+/// or in the human-readable form with the AIs in parentheses. Plain EANs are not GS1 codes.
+pub fn parse_gs1(code: &str) -> Option<Gs1Data> {
+    // scanners may prefix the symbology identifier, e.g. `]C1` for GS1-128
+    let code = match code.strip_prefix(']') {
+        Some(rest) => rest.get(2..)?,
+        None => code,
+    };
+    let fields = if code.starts_with('(') {
+        parse_gs1_parentheses(code)?
+    } else {
+        parse_gs1_raw(code)?
+    };
+    let mut gtin = None;
+    let mut expiry = None;
+    let mut best_before = None;
+    for (ai, value) in fields {
+        match ai {
+            "01" => gtin = Some(value),
+            "17" => expiry = Some(parse_gs1_date(value)?),
+            "15" => best_before = Some(parse_gs1_date(value)?),
+            _ => {}
+        }
+    }
+    let gtin = gtin?;
+    if gtin.len() != 14 || !gtin.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // a GTIN-14 without packaging indicator is just a zero-padded EAN-13
+    let ean = gtin.strip_prefix('0').unwrap_or(gtin).to_string();
+    Some(Gs1Data {
+        ean,
+        expiry: expiry.or(best_before),
+    })
+}
+
+fn parse_gs1_parentheses(code: &str) -> Option<Vec<(&str, &str)>> {
+    let mut fields = Vec::new();
+    let mut rest = code;
+    while !rest.is_empty() {
+        let (ai, tail) = rest.strip_prefix('(')?.split_once(')')?;
+        let end = tail.find('(').unwrap_or(tail.len());
+        fields.push((ai, &tail[..end]));
+        rest = &tail[end..];
+    }
+    Some(fields)
+}
+
+fn parse_gs1_raw(code: &str) -> Option<Vec<(&str, &str)>> {
+    // a GTIN-14 and its AI are longer than any EAN, so this does not catch plain EANs
+    const SEPARATOR: char = '\x1d';
+    if !code.starts_with("01") || code.len() < 16 {
+        return None;
+    }
+    let mut fields = Vec::new();
+    let mut rest = code;
+    while !rest.is_empty() {
+        let ai = rest.get(..2)?;
+        let len = match ai {
+            "01" => Some(14),
+            "11" | "13" | "15" | "17" => Some(6),
+            // variable length, e.g. the batch (10) or serial number (21)
+            "10" | "21" => None,
+            // without knowing the length of unknown AIs, we cannot find the next one
+            _ => break,
+        };
+        let tail = &rest[2..];
+        let value = match len {
+            Some(len) => tail.get(..len)?,
+            None => &tail[..tail.find(SEPARATOR).unwrap_or(tail.len())],
+        };
+        fields.push((ai, value));
+        rest = tail[value.len()..].trim_start_matches(SEPARATOR);
+    }
+    Some(fields)
+}
+
+/// Parse a GS1 `YYMMDD` date. A day of `00` means the end of the month.
+fn parse_gs1_date(value: &str) -> Option<NaiveDate> {
+    if value.len() != 6 || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let year = 2000 + value[..2].parse::<i32>().ok()?;
+    let month = value[2..4].parse().ok()?;
+    let day: u32 = value[4..].parse().ok()?;
+    if day == 0 {
+        let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+        return first.checked_add_months(Months::new(1))?.pred_opt();
+    }
+    NaiveDate::from_ymd_opt(year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        assert_eq!(encode_stock_code(12, 34), "~1:12|34~");
+        assert_eq!(
+            decode_stock_code(&encode_stock_code(12, 34)),
+            Some((12, 34))
+        );
+    }
+
+    #[test]
+    fn decode_valid_code() {
+        assert_eq!(decode_stock_code("~1:12|34~"), Some((12, 34)));
+        assert_eq!(decode_stock_code("~12|34~"), Some((12, 34)));
+        assert_eq!(decode_stock_code("~1|1~"), Some((1, 1)));
+        assert_eq!(decode_stock_code("~007|0~"), Some((7, 0)));
+    }
+
+    #[test]
+    fn decode_unknown_version() {
+        assert_eq!(decode_stock_code("~2:12|34~"), None);
+        assert_eq!(decode_stock_code("~:12|34~"), None);
+        assert_eq!(decode_stock_code("~v1:12|34~"), None);
+    }
+
+    #[test]
+    fn decode_missing_parts() {
+        assert_eq!(decode_stock_code("~12|~"), None);
+        assert_eq!(decode_stock_code("~|34~"), None);
+        assert_eq!(decode_stock_code("~|~"), None);
+        assert_eq!(decode_stock_code("~1234~"), None);
+        assert_eq!(decode_stock_code("~1:~"), None);
+        assert_eq!(decode_stock_code("~~"), None);
+        assert_eq!(decode_stock_code(""), None);
+    }
+
+    #[test]
+    fn decode_not_numeric() {
+        assert_eq!(decode_stock_code("~abc|def~"), None);
+        assert_eq!(decode_stock_code("~12|def~"), None);
+        assert_eq!(decode_stock_code("~-1|34~"), None);
+        assert_eq!(decode_stock_code("~+1|34~"), None);
+        assert_eq!(decode_stock_code("~ 12|34~"), None);
+        assert_eq!(decode_stock_code("~99999999999|34~"), None);
+    }
+
+    #[test]
+    fn decode_extra_characters() {
+        assert_eq!(decode_stock_code("~12|34~5"), None);
+        assert_eq!(decode_stock_code("x~12|34~"), None);
+        assert_eq!(decode_stock_code("~12|34"), None);
+        assert_eq!(decode_stock_code("12|34~"), None);
+        assert_eq!(decode_stock_code("~12|34|56~"), None);
+        assert_eq!(decode_stock_code("~12|34~~"), None);
+        assert_eq!(decode_stock_code("~1:1:12|34~"), None);
+    }
+
+    #[test]
+    fn item_code() {
+        assert_eq!(encode_item_code(12), "~1:12~");
+        assert_eq!(decode_item_code(&encode_item_code(12)), Some(12));
+        assert_eq!(decode_item_code("~12~"), Some(12));
+        assert_eq!(decode_item_code("~2:12~"), None);
+        assert_eq!(decode_item_code("~1:~"), None);
+        assert_eq!(decode_item_code("~-12~"), None);
+        assert_eq!(decode_item_code("~12~3"), None);
+        // stock codes are not item codes and vice versa
+        assert_eq!(decode_item_code("~1:12|34~"), None);
+        assert_eq!(decode_stock_code("~1:12~"), None);
+    }
+
+    #[test]
+    fn decode_rejects_op_codes() {
+        for code in [
+            "~+~",
+            "~=~",
+            "~#~",
+            "???",
+            "+++",
+            ">>>",
+            "<->",
+            "4006381333931",
+        ] {
+            assert_eq!(decode_stock_code(code), None, "{code}");
+            assert_eq!(decode_item_code(code), None, "{code}");
+        }
+    }
+
+    #[test]
+    fn gs1_raw() {
+        assert_eq!(
+            parse_gs1("01040063813339311726123110ABC123"),
+            Some(Gs1Data {
+                ean: "4006381333931".to_string(),
+                expiry: NaiveDate::from_ymd_opt(2026, 12, 31),
+            })
+        );
+        assert_eq!(
+            parse_gs1("]C1010400638133393110ABC\x1d17260300"),
+            Some(Gs1Data {
+                ean: "4006381333931".to_string(),
+                expiry: NaiveDate::from_ymd_opt(2026, 3, 31),
+            })
+        );
+    }
+
+    #[test]
+    fn gs1_parentheses() {
+        assert_eq!(
+            parse_gs1("(01)04006381333931(15)260115"),
+            Some(Gs1Data {
+                ean: "4006381333931".to_string(),
+                expiry: NaiveDate::from_ymd_opt(2026, 1, 15),
+            })
+        );
+        assert_eq!(
+            parse_gs1("(01)14006381333938"),
+            Some(Gs1Data {
+                ean: "14006381333938".to_string(),
+                expiry: None,
+            })
+        );
+    }
+
+    #[test]
+    fn gs1_prefers_expiry_over_best_before() {
+        let data = parse_gs1("(01)04006381333931(15)260115(17)260120").unwrap();
+        assert_eq!(data.expiry, NaiveDate::from_ymd_opt(2026, 1, 20));
+    }
+
+    #[test]
+    fn gs1_rejects_other_codes() {
+        assert_eq!(parse_gs1("4006381333931"), None);
+        assert_eq!(parse_gs1("0123456789012"), None);
+        assert_eq!(parse_gs1("~1:12|34~"), None);
+        assert_eq!(parse_gs1("(17)260120"), None);
+        assert_eq!(parse_gs1("(01)0400638133393"), None);
+        assert_eq!(parse_gs1("(01)04006381333931(17)261332"), None);
+    }
+}
@@ -0,0 +1,247 @@
+use anyhow::Result;
+use chrono::TimeDelta;
+
+use crate::labels::{CodeKind, LabelMedia};
+use std::env;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug)]
+pub struct Config {
+    pub scan_codes: ScanCodes,
+    /// Whether to only pretend to print labels, e.g. for testing without a printer.
+    pub dry_run: bool,
+    /// A shell command run after each scan instead of ringing the terminal bell, e.g. to drive
+    /// a buzzer. `LARDER_FEEDBACK` is set to `success` or `failure`.
+    pub feedback_command: Option<String>,
+    /// Identical scans from the same device within this window are treated as the scanner
+    /// firing twice and ignored.
+    pub debounce: Duration,
+    /// How long the scanner has to be idle before the scan op is reset.
+    pub idle_timeout: Duration,
+    /// Whether to ask before registering an unknown item that is being added to stock.
+    pub confirm_auto_register: bool,
+    /// How long to wait for a database connection before giving up.
+    pub db_timeout: Duration,
+    /// Whether to apply pending database migrations at startup.
+    pub auto_migrate: bool,
+    /// Port of the web API, which is disabled if not set.
+    pub web_port: Option<u16>,
+    /// Whether the web API also serves Prometheus metrics.
+    pub metrics: bool,
+    /// Whether the web API also accepts requests that change data. It has no authentication, so
+    /// this should only be enabled on a trusted network.
+    pub web_allow_changes: bool,
+    /// A Unix socket on which every stock change is published as a line of JSON.
+    pub event_socket: Option<PathBuf>,
+    /// How long openfoodfacts lookup results are cached.
+    pub off_cache_ttl: TimeDelta,
+    /// How long to wait for an openfoodfacts response.
+    pub off_timeout: Duration,
+    /// How often to retry a failed openfoodfacts lookup.
+    pub off_retries: u32,
+    /// Whether to query the v2 openfoodfacts API before falling back to v0.
+    pub off_use_v2: bool,
+    /// Whether to look up the name of newly registered items on openfoodfacts instead of
+    /// asking for it right away.
+    pub off_lookup: bool,
+    /// A JSON file mapping EANs to product names, which take precedence over openfoodfacts.
+    pub name_overrides: Option<PathBuf>,
+    /// A file to keep the current scan op in, so it survives a restart.
+    pub state_file: Option<PathBuf>,
+    /// Whether custom items are also found by similar names, which needs the pg_trgm extension.
+    pub fuzzy_search: bool,
+    /// Whether more than one unit of an item may be open at the same time.
+    pub allow_multiple_open: bool,
+    /// Whether opening marks all available units of an item as open instead of a single one.
+    pub open_whole_batch: bool,
+    /// The kind of 2D code printed on labels.
+    pub label_code: CodeKind,
+    /// The label roll loaded in the printer.
+    pub label_media: LabelMedia,
+    /// Whether to also print a label when adding bought (EAN) items to stock.
+    pub print_bought_labels: bool,
+    /// A typst file used instead of the embedded label template.
+    pub label_template: Option<PathBuf>,
+    /// Names longer than this many characters are truncated on labels. Shorter ones are wrapped
+    /// by the template.
+    pub label_max_name_len: usize,
+    /// A directory with additional fonts that can be used in the label template.
+    pub label_font_dir: Option<PathBuf>,
+    /// If set, labels are queued and printed together with any other labels queued within
+    /// this time.
+    pub label_batch_window: Option<Duration>,
+    /// Which units are removed or opened first.
+    pub stock_order: StockOrder,
+    /// Which units are taken out first when lowering the stock count to a given number.
+    pub set_count_order: StockOrder,
+}
+
+/// The order in which stock entries are used up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StockOrder {
+    /// Oldest first
+    Fifo,
+    /// Newest first
+    Lifo,
+}
+
+impl FromStr for StockOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fifo" | "oldest" => Ok(StockOrder::Fifo),
+            "lifo" | "newest" => Ok(StockOrder::Lifo),
+            _ => Err("expected 'fifo' or 'lifo'".to_string()),
+        }
+    }
+}
+
+/// The contents of the special barcodes that change the scan op or trigger an action.
+#[derive(Debug)]
+pub struct ScanCodes {
+    pub none: String,
+    pub register: String,
+    pub add: String,
+    pub remove: String,
+    pub open: String,
+    pub finish: String,
+    pub min_stock: String,
+    pub open_shelf_days: String,
+    pub remaining: String,
+    pub rename: String,
+    pub note: String,
+    pub reprint: String,
+    pub delete: String,
+    pub move_location: String,
+    pub set_count: String,
+    pub count: String,
+    pub create_custom: String,
+    pub list_expiring: String,
+    pub shopping_list: String,
+    pub merge: String,
+    pub remove_alias: String,
+    pub undo: String,
+}
+
+impl Config {
+    /// Load the configuration from the environment, failing if a variable has an invalid value.
+    pub fn from_env() -> Result<Self> {
+        let stock_order = env_parse("STOCK_ORDER")?.unwrap_or(StockOrder::Fifo);
+        let mut codes = Vec::new();
+        let mut code = |key: &'static str, default: &str| {
+            let value = env_or(key, default);
+            codes.push((key, value.clone()));
+            value
+        };
+        let scan_codes = ScanCodes {
+            none: code("OP_NONE", "???"),
+            register: code("OP_REGISTER", "+++"),
+            add: code("OP_ADD", ">>>"),
+            remove: code("OP_REMOVE", "<<<"),
+            open: code("OP_OPEN", "///"),
+            finish: code("OP_FINISH", "</<"),
+            min_stock: code("OP_MIN_STOCK", "=<="),
+            open_shelf_days: code("OP_OPEN_SHELF_DAYS", "=/="),
+            remaining: code("OP_REMAINING", "/%/"),
+            rename: code("OP_RENAME", "~=~"),
+            note: code("OP_NOTE", "~!~"),
+            reprint: code("OP_REPRINT", "~#~"),
+            delete: code("OP_DELETE", "-X-"),
+            move_location: code("OP_MOVE", "<=>"),
+            set_count: code("OP_SET_COUNT", "#=#"),
+            count: code("OP_COUNT", "###"),
+            create_custom: code("OP_CREATE_CUSTOM", "~+~"),
+            list_expiring: code("OP_LIST_EXPIRING", "!!!"),
+            shopping_list: code("OP_SHOPPING_LIST", "$$$"),
+            merge: code("OP_MERGE", ">+<"),
+            remove_alias: code("OP_REMOVE_ALIAS", ">-<"),
+            undo: code("OP_UNDO", "<->"),
+        };
+        // only the first op with a code could ever be triggered
+        for (i, (key, value)) in codes.iter().enumerate() {
+            if let Some((other, _)) = codes[..i].iter().find(|(_, other)| other == value) {
+                anyhow::bail!("{other} and {key} have the same value '{value}'");
+            }
+        }
+        Ok(Self {
+            scan_codes,
+            dry_run: env_flag("LARDER_DRY_RUN"),
+            feedback_command: env::var("FEEDBACK_COMMAND").ok(),
+            debounce: Duration::from_millis(env_parse("DEBOUNCE_MS")?.unwrap_or(300)),
+            idle_timeout: Duration::from_secs(env_parse("IDLE_TIMEOUT_SECS")?.unwrap_or(120)),
+            confirm_auto_register: env_flag("CONFIRM_AUTO_REGISTER"),
+            db_timeout: Duration::from_secs(env_parse("DB_TIMEOUT_SECS")?.unwrap_or(5)),
+            auto_migrate: env_flag("LARDER_AUTO_MIGRATE"),
+            web_port: env_parse("WEB_PORT")?,
+            metrics: env_flag("METRICS"),
+            web_allow_changes: env_flag("WEB_ALLOW_CHANGES"),
+            event_socket: env_parse("EVENT_SOCKET")?,
+            off_cache_ttl: TimeDelta::days(env_parse("OFF_CACHE_TTL_DAYS")?.unwrap_or(30)),
+            off_timeout: Duration::from_secs(env_parse("OFF_TIMEOUT_SECS")?.unwrap_or(10)),
+            off_retries: env_parse("OFF_RETRIES")?.unwrap_or(2),
+            off_use_v2: env_parse("OFF_USE_V2")?.unwrap_or(true),
+            off_lookup: env_parse("OFF_LOOKUP")?.unwrap_or(true),
+            name_overrides: env_parse("NAME_OVERRIDES")?,
+            state_file: env_parse("STATE_FILE")?,
+            fuzzy_search: env_flag("FUZZY_SEARCH"),
+            allow_multiple_open: env_flag("ALLOW_MULTIPLE_OPEN"),
+            open_whole_batch: env_flag("OPEN_WHOLE_BATCH"),
+            label_code: env_parse("LABEL_CODE")?.unwrap_or(CodeKind::DataMatrix),
+            label_media: env_parse("LABEL_MEDIA")?.unwrap_or(LabelMedia::C62),
+            print_bought_labels: env_flag("PRINT_BOUGHT_LABELS"),
+            label_template: env_parse("LABEL_TEMPLATE")?,
+            label_max_name_len: env_parse("LABEL_MAX_NAME_LEN")?.unwrap_or(60),
+            label_font_dir: env_parse("LABEL_FONT_DIR")?,
+            label_batch_window: env_parse("LABEL_BATCH_WINDOW_SECS")?.map(Duration::from_secs),
+            stock_order,
+            set_count_order: env_parse("SET_COUNT_ORDER")?.unwrap_or(stock_order),
+        })
+    }
+}
+
+/// Get the config, which is loaded from the environment on first use unless it has been
+/// initialized before. Loading it here panics on invalid values, so binaries should use
+/// `init_config` to report them properly.
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(|| Config::from_env().unwrap_or_else(|err| panic!("{err}")))
+}
+
+/// Use a configuration that differs from the environment, e.g. due to command line flags. This
+/// must happen before the configuration is used for the first time.
+pub fn init_config(config: Config) {
+    if CONFIG.set(config).is_err() {
+        panic!("Configuration was used before being initialized");
+    }
+}
+
+fn env_or(key: &str, default: &str) -> String {
+    env::var(key)
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
+
+fn env_parse<T>(key: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let Some(value) = env::var(key).ok().filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    value
+        .parse()
+        .map(Some)
+        .map_err(|err| anyhow::anyhow!("{key} has an invalid value '{value}': {err}"))
+}
+
+fn env_flag(key: &str) -> bool {
+    env::var(key).is_ok_and(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+}
@@ -1,116 +1,524 @@
 use anyhow::Result;
-use diesel::{dsl::now, prelude::*, sql_query, sql_types::Integer};
+use chrono::{DateTime, Local, NaiveDate, TimeDelta};
+use diesel::{
+    dsl::{count_star, now, sum},
+    prelude::*,
+    r2d2::{ConnectionManager, Pool, PooledConnection, R2D2Connection},
+};
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
 
-use crate::models::{Alias, Item, ItemKind, NewItem, Stock, lower};
+use crate::config::{StockOrder, config};
+use crate::models::{
+    Backup, Item, ItemDetails, ItemEan, ItemKind, NewItem, OffCacheEntry, ShoppingListEntry, Stock,
+    StockAction, StockLogEntry, lower, lower_nullable, similarity,
+};
 
-pub fn connect_db() -> Result<PgConnection> {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&database_url)
-        .map_err(|err| anyhow::anyhow!("Error connecting to {database_url}: {err}"))
+pub type DbConnection = PooledConnection<ConnectionManager<PgConnection>>;
+
+static POOL: OnceLock<Pool<ConnectionManager<PgConnection>>> = OnceLock::new();
+
+fn pool() -> Result<&'static Pool<ConnectionManager<PgConnection>>> {
+    if let Some(pool) = POOL.get() {
+        return Ok(pool);
+    }
+    let database_url = env::var("DATABASE_URL").map_err(|_| {
+        anyhow::anyhow!(
+            "DATABASE_URL is not set, set it in the environment or in a .env file in the \
+             current directory"
+        )
+    })?;
+    // connections are checked before being handed out, so ones that died e.g. due to
+    // a database restart are replaced instead of failing the next query
+    let pool = Pool::builder()
+        .test_on_check_out(true)
+        .connection_timeout(config().db_timeout)
+        .build(ConnectionManager::new(&database_url))
+        .map_err(|err| anyhow::anyhow!("Error connecting to {database_url}: {err}"))?;
+    Ok(POOL.get_or_init(|| pool))
+}
+
+/// Set up the connection pool, so the first scan does not have to wait for it.
+pub fn init_pool() -> Result<()> {
+    pool().map(|_| ())
+}
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// Apply all migrations that have not been applied to the database yet.
+pub fn run_migrations() -> Result<()> {
+    let conn = &mut connect_db()?;
+    let applied = conn
+        .run_pending_migrations(MIGRATIONS)
+        .map_err(|err| anyhow::anyhow!("Could not run migrations: {err}"))?;
+    for version in applied {
+        info!("applied migration {version}");
+    }
+    Ok(())
+}
+
+/// Get a connection from the pool. If none can be established, e.g. because the database is
+/// restarting, this is retried once before giving up.
+pub fn connect_db() -> Result<DbConnection> {
+    let pool = pool()?;
+    pool.get()
+        .or_else(|err| {
+            warn!("could not get database connection, retrying: {err}");
+            thread::sleep(RECONNECT_DELAY);
+            pool.get()
+        })
+        .map_err(|err| anyhow::anyhow!("Could not get database connection: {err}"))
+}
+
+/// Run a database operation on a pooled connection. If it fails and the connection turns out
+/// to be dead, e.g. because the database was restarted, it is run once more on a fresh one.
+/// Operations only read or make their changes in a single statement or transaction, so the
+/// failed attempt did not change anything.
+fn with_connection<T>(mut op: impl FnMut(&mut DbConnection) -> Result<T>) -> Result<T> {
+    let mut conn = connect_db()?;
+    match op(&mut conn) {
+        Err(err) if conn.ping().is_err() => {
+            warn!("database connection lost, retrying: {err}");
+            // give it back first so the pool can discard it
+            drop(conn);
+            op(&mut connect_db()?)
+        }
+        res => res,
+    }
+}
+
+/// Run a database operation on the given connection, or on a pooled one if there is none. The
+/// caller owning the connection is responsible for retrying.
+fn with_db<T>(
+    conn: Option<&mut DbConnection>,
+    mut op: impl FnMut(&mut DbConnection) -> Result<T>,
+) -> Result<T> {
+    match conn {
+        Some(conn) => op(conn),
+        None => with_connection(op),
+    }
+}
+
+/// Get the spellings under which a scanned EAN may be known: as scanned, zero-padded to 13
+/// digits (UPC-A/EAN-8) and with a leading zero stripped (GTIN-14/UPC-A stored as EAN-13).
+pub fn ean_variants(barcode_ean: &str) -> Vec<String> {
+    let mut variants = vec![barcode_ean.to_string()];
+    if barcode_ean.len() < 13 {
+        variants.push(format!("{barcode_ean:0>13}"));
+    }
+    if let Some(stripped) = barcode_ean.strip_prefix('0') {
+        variants.push(stripped.to_string());
+    }
+    variants.dedup();
+    variants
 }
 
 pub fn query_item_by_ean(barcode_ean: &str) -> Result<Option<Item>> {
+    use crate::schema::item_eans;
     use crate::schema::items::dsl::*;
 
-    let conn = &mut connect_db()?;
-    let barcode_ean = query_ean_by_alias(conn, barcode_ean)?.unwrap_or(barcode_ean.to_string());
+    with_connection(|conn| {
+        for variant in ean_variants(barcode_ean) {
+            // extra EANs of deleted items are kept so they can be restored, so they must not hide
+            // an item that has been registered with the same EAN since
+            let item = items
+                .filter(deleted_dt.is_null())
+                .filter(
+                    ean.eq(variant.as_str()).or(id.eq_any(
+                        item_eans::table
+                            .filter(item_eans::ean.eq(variant.as_str()))
+                            .select(item_eans::item_id),
+                    )),
+                )
+                .select(Item::as_select())
+                .first(conn)
+                .optional()
+                .map_err(|err| anyhow::anyhow!("Could not load item {variant}: {err}"))?;
+            if item.is_some() {
+                return Ok(item);
+            }
+        }
+        Ok(None)
+    })
+}
 
-    items
-        .filter(ean.eq(barcode_ean.as_str()))
-        .select(Item::as_select())
-        .first(conn)
-        .optional()
-        .map_err(|err| anyhow::anyhow!("Could not load item {barcode_ean}: {err}"))
+/// Get the most recently deleted item with the given EAN, either as its own or as an extra one.
+pub fn query_deleted_item_by_ean(barcode_ean: &str) -> Result<Option<Item>> {
+    use crate::schema::item_eans;
+    use crate::schema::items::dsl::*;
+
+    with_connection(|conn| {
+        items
+            .filter(
+                ean.eq(barcode_ean).or(id.eq_any(
+                    item_eans::table
+                        .filter(item_eans::ean.eq(barcode_ean))
+                        .select(item_eans::item_id),
+                )),
+            )
+            .filter(deleted_dt.is_not_null())
+            .order(deleted_dt.desc())
+            .select(Item::as_select())
+            .first(conn)
+            .optional()
+            .map_err(|err| anyhow::anyhow!("Could not load deleted item {barcode_ean}: {err}"))
+    })
 }
 
-fn query_ean_by_alias(conn: &mut PgConnection, alias_ean: &str) -> Result<Option<String>> {
-    use crate::schema::aliases::dsl::*;
+/// Get the id of the live item that has the given EAN as an additional EAN.
+fn query_item_id_by_alias(conn: &mut PgConnection, alias_ean: &str) -> Result<Option<i32>> {
+    use crate::schema::{item_eans, items};
 
-    aliases
-        .find(alias_ean)
-        .select(Alias::as_select())
+    item_eans::table
+        .inner_join(items::table)
+        .filter(item_eans::ean.eq(alias_ean))
+        .filter(items::deleted_dt.is_null())
+        .select(item_eans::item_id)
         .first(conn)
         .optional()
         .map_err(|err| anyhow::anyhow!("Could not load alias for {alias_ean}: {err}"))
-        .map(|opt| opt.map(|a| a.alias_for))
 }
 
 pub fn query_item_by_name(ci_name: &str) -> Result<Option<Item>> {
     use crate::schema::items::dsl::*;
 
-    let conn = &mut connect_db()?;
-    items
-        .filter(lower(name).eq(lower(ci_name)))
-        .select(Item::as_select())
-        .first(conn)
-        .optional()
-        .map_err(|err| anyhow::anyhow!("Could not check for similar item: {err}"))
+    with_connection(|conn| {
+        items
+            .filter(lower(name).eq(lower(ci_name)))
+            .filter(deleted_dt.is_null())
+            .select(Item::as_select())
+            .first(conn)
+            .optional()
+            .map_err(|err| anyhow::anyhow!("Could not check for similar item: {err}"))
+    })
 }
 
 pub fn query_item_by_id(id: i32) -> Result<Option<Item>> {
     use crate::schema::items::dsl::items;
 
-    let conn = &mut connect_db()?;
-    items
-        .find(id)
-        .select(Item::as_select())
-        .first(conn)
-        .optional()
-        .map_err(|err| anyhow::anyhow!("Could not get item: {err}"))
+    with_connection(|conn| {
+        items
+            .find(id)
+            .select(Item::as_select())
+            .first(conn)
+            .optional()
+            .map_err(|err| anyhow::anyhow!("Could not get item: {err}"))
+    })
 }
 
 #[derive(Debug)]
-pub struct StockInfo {
+pub struct StockSummary {
+    pub total: i64,
     pub opened: i64,
-    pub unopened: i64,
+    pub available: i64,
+    pub oldest_added_dt: Option<DateTime<Local>>,
+    pub next_expiry_dt: Option<NaiveDate>,
+    /// Approximately how many of the opened units are left, based on their remaining fraction.
+    pub opened_remaining: f64,
 }
 
-pub fn query_item_stock(item_id: i32) -> Result<StockInfo> {
+pub fn query_stock_summary(item: &Item) -> Result<StockSummary> {
     use crate::schema::stock::dsl;
+    use diesel::dsl::min;
 
-    let conn = &mut connect_db()?;
-    let num_opened = dsl::stock
-        .filter(
-            dsl::item_id
-                .eq(item_id)
-                .and(dsl::removed_dt.is_null())
-                .and(dsl::opened_dt.is_not_null()),
-        )
-        .count()
-        .get_result(conn)
-        .map_err(|err| anyhow::anyhow!("Could not get item: {err}"))?;
-    let num_unopened = dsl::stock
-        .filter(
-            dsl::item_id
-                .eq(item_id)
-                .and(dsl::removed_dt.is_null())
-                .and(dsl::opened_dt.is_null()),
-        )
-        .count()
-        .get_result(conn)
-        .map_err(|err| anyhow::anyhow!("Could not get item: {err}"))?;
+    with_connection(|conn| {
+        let num_opened: Option<i64> = dsl::stock
+            .filter(
+                dsl::item_id
+                    .eq(item.id)
+                    .and(dsl::removed_dt.is_null())
+                    .and(dsl::opened_dt.is_not_null()),
+            )
+            .select(sum(dsl::quantity))
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not get stock: {err}"))?;
+        let num_available: Option<i64> = dsl::stock
+            .filter(
+                dsl::item_id
+                    .eq(item.id)
+                    .and(dsl::removed_dt.is_null())
+                    .and(dsl::opened_dt.is_null()),
+            )
+            .select(sum(dsl::quantity))
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not get stock: {err}"))?;
+        let oldest_added_dt = dsl::stock
+            .filter(dsl::item_id.eq(item.id).and(dsl::removed_dt.is_null()))
+            .select(min(dsl::added_dt))
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not get stock: {err}"))?;
+        let next_expiry_dt = dsl::stock
+            .filter(dsl::item_id.eq(item.id).and(dsl::removed_dt.is_null()))
+            .select(min(dsl::expiry_dt))
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not get stock: {err}"))?;
+        let opened_entries: Vec<(i32, Option<f32>)> = dsl::stock
+            .filter(
+                dsl::item_id
+                    .eq(item.id)
+                    .and(dsl::removed_dt.is_null())
+                    .and(dsl::opened_dt.is_not_null()),
+            )
+            .select((dsl::quantity, dsl::remaining_fraction))
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not get stock: {err}"))?;
+        // units without a known fraction are assumed to be full
+        let opened_remaining = opened_entries
+            .iter()
+            .map(|&(quantity, fraction)| f64::from(quantity) * f64::from(fraction.unwrap_or(1.0)))
+            .sum();
+
+        let opened = num_opened.unwrap_or(0);
+        let available = num_available.unwrap_or(0);
+        Ok(StockSummary {
+            total: opened + available,
+            opened,
+            available,
+            oldest_added_dt,
+            next_expiry_dt,
+            opened_remaining,
+        })
+    })
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemOrder {
+    #[default]
+    Name,
+    Id,
+}
+
+/// Get a page of all items, optionally only those in a category (ignoring case). Deleted items
+/// are only included if requested.
+pub fn query_all_items(
+    ci_category: Option<&str>,
+    limit: i64,
+    offset: i64,
+    order: ItemOrder,
+    include_deleted: bool,
+) -> Result<Vec<Item>> {
+    use crate::schema::items::dsl::*;
+
+    with_connection(|conn| {
+        let mut query = items.select(Item::as_select()).into_boxed();
+        if let Some(ci_category) = ci_category {
+            query = query.filter(lower_nullable(category).eq(ci_category.to_lowercase()));
+        }
+        if !include_deleted {
+            query = query.filter(deleted_dt.is_null());
+        }
+        query = match order {
+            ItemOrder::Name => query.order((lower(name), id)),
+            ItemOrder::Id => query.order(id),
+        };
+        query
+            .limit(limit)
+            .offset(offset)
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not query items: {err}"))
+    })
+}
+
+pub fn count_items(include_deleted: bool) -> Result<i64> {
+    use crate::schema::items::dsl::*;
+
+    with_connection(|conn| {
+        let mut query = items.into_boxed();
+        if !include_deleted {
+            query = query.filter(deleted_dt.is_null());
+        }
+        query
+            .count()
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not count items: {err}"))
+    })
+}
+
+/// Get the number of unopened and opened units across all items.
+pub fn query_stock_totals() -> Result<(i64, i64)> {
+    use crate::schema::stock::dsl;
 
-    Ok(StockInfo {
-        opened: num_opened,
-        unopened: num_unopened,
+    with_connection(|conn| {
+        let available: Option<i64> = dsl::stock
+            .filter(dsl::removed_dt.is_null().and(dsl::opened_dt.is_null()))
+            .select(sum(dsl::quantity))
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not get stock: {err}"))?;
+        let opened: Option<i64> = dsl::stock
+            .filter(dsl::removed_dt.is_null().and(dsl::opened_dt.is_not_null()))
+            .select(sum(dsl::quantity))
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not get stock: {err}"))?;
+        Ok((available.unwrap_or(0), opened.unwrap_or(0)))
     })
 }
 
+/// Get all stock entries along with their items, optionally only those still in stock.
+pub fn query_inventory(active_only: bool) -> Result<Vec<(Item, Stock)>> {
+    use crate::schema::{items, stock};
+
+    with_connection(|conn| {
+        let mut query = stock::table
+            .inner_join(items::table)
+            .select((Item::as_select(), Stock::as_select()))
+            .order((lower(items::name), stock::added_dt.asc()))
+            .into_boxed();
+        if active_only {
+            query = query.filter(stock::removed_dt.is_null());
+        }
+        query
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not query inventory: {err}"))
+    })
+}
+
+/// Get all stock entries of an item that have not been removed yet.
+pub fn query_stock_entries(item: &Item) -> Result<Vec<Stock>> {
+    use crate::schema::stock::dsl;
+
+    with_connection(|conn| {
+        dsl::stock
+            .filter(dsl::item_id.eq(item.id).and(dsl::removed_dt.is_null()))
+            .order(dsl::added_dt.asc())
+            .select(Stock::as_select())
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not get stock: {err}"))
+    })
+}
+
+pub fn query_stock_by_id(id: i32) -> Result<Option<Stock>> {
+    use crate::schema::stock::dsl::stock;
+
+    with_connection(|conn| {
+        stock
+            .find(id)
+            .select(Stock::as_select())
+            .first(conn)
+            .optional()
+            .map_err(|err| anyhow::anyhow!("Could not get stock: {err}"))
+    })
+}
+
+/// Minimum trigram similarity for a name to count as a match in the fuzzy search.
+const FUZZY_THRESHOLD: f32 = 0.3;
+
 pub fn search_custom_items_by_name(ci_name: &str) -> Result<Vec<Item>> {
     use crate::schema::items::dsl::*;
 
-    let conn = &mut connect_db()?;
-    items
-        .filter(name.ilike(format!("%{ci_name}%")))
-        .filter(kind.eq(ItemKind::Custom))
-        .select(Item::as_select())
-        .order(lower(name))
-        .load(conn)
-        .map_err(|err| anyhow::anyhow!("Could not query custom items: {err}"))
+    with_connection(|conn| {
+        if config().fuzzy_search {
+            let similar = items
+                .filter(
+                    name.ilike(format!("%{ci_name}%"))
+                        .or(similarity(name, ci_name).gt(FUZZY_THRESHOLD)),
+                )
+                .filter(kind.eq(ItemKind::Custom))
+                .filter(deleted_dt.is_null())
+                .select(Item::as_select())
+                .order(similarity(name, ci_name).desc())
+                .load(conn);
+            match similar {
+                Ok(found) => return Ok(found),
+                // most likely pg_trgm is not installed, so the plain search is the best we can do
+                Err(err) => warn!("fuzzy search failed, falling back to plain search: {err}"),
+            }
+        }
+        items
+            .filter(name.ilike(format!("%{ci_name}%")))
+            .filter(kind.eq(ItemKind::Custom))
+            .filter(deleted_dt.is_null())
+            .select(Item::as_select())
+            .order(lower(name))
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not query custom items: {err}"))
+    })
+}
+
+/// Get all items whose EAN starts with the given digits, e.g. to find an item whose barcode
+/// is damaged.
+pub fn query_items_by_ean_prefix(prefix: &str) -> Result<Vec<Item>> {
+    use crate::schema::items::dsl::*;
+
+    with_connection(|conn| {
+        items
+            .filter(ean.like(format!("{prefix}%")))
+            .filter(deleted_dt.is_null())
+            .select(Item::as_select())
+            .order(ean)
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not query items by EAN prefix: {err}"))
+    })
+}
+
+/// Get all items whose available stock is below their minimum stock, along with the
+/// available stock.
+pub fn query_low_stock() -> Result<Vec<(Item, i64)>> {
+    use crate::schema::{items, stock};
+
+    with_connection(|conn| {
+        let counts: Vec<(Item, Option<i64>)> = items::table
+            .left_join(
+                stock::table.on(stock::item_id
+                    .eq(items::id)
+                    .and(stock::removed_dt.is_null())
+                    .and(stock::opened_dt.is_null())),
+            )
+            .filter(items::min_stock.is_not_null())
+            .filter(items::deleted_dt.is_null())
+            .group_by(items::id)
+            .order(lower(items::name))
+            .select((Item::as_select(), sum(stock::quantity.nullable())))
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not query low stock: {err}"))?;
+        Ok(counts
+            .into_iter()
+            .map(|(item, available)| (item, available.unwrap_or(0)))
+            .filter(|(item, available)| {
+                item.min_stock
+                    .is_some_and(|min| *available < i64::from(min))
+            })
+            .collect())
+    })
 }
 
-pub fn create_item(barcode_ean: Option<&str>, name: &str) -> Result<Item> {
+/// Get all items that had stock removed since the given time, along with the removed quantity.
+pub fn query_consumption(since: DateTime<Local>) -> Result<Vec<(Item, i64)>> {
+    use crate::schema::{items, stock, stock_log};
+
+    // every removed or finished unit is logged, while the stock entries only keep what is left
+    with_connection(|conn| {
+        items::table
+            .inner_join(stock::table.inner_join(stock_log::table))
+            .filter(
+                stock_log::action
+                    .eq(StockAction::Remove)
+                    .or(stock_log::action.eq(StockAction::Finish)),
+            )
+            .filter(stock_log::occurred_dt.ge(since))
+            .group_by(items::id)
+            .order(lower(items::name))
+            .select((Item::as_select(), count_star()))
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not query consumption: {err}"))
+    })
+}
+
+pub fn create_item(
+    barcode_ean: Option<&str>,
+    name: &str,
+    details: &ItemDetails,
+    conn: Option<&mut DbConnection>,
+) -> Result<Item> {
     use crate::schema::items;
 
     let new_item = NewItem {
@@ -121,159 +529,1520 @@ pub fn create_item(barcode_ean: Option<&str>, name: &str) -> Result<Item> {
             ItemKind::Custom
         },
         ean: barcode_ean,
+        brand: details.brand.as_deref(),
+        quantity: details.quantity.as_deref(),
+        category: details.category.as_deref(),
     };
 
-    let conn = &mut connect_db()?;
-    diesel::insert_into(items::table)
-        .values(&new_item)
-        .returning(Item::as_returning())
-        .get_result(conn)
-        .map_err(|err| anyhow::anyhow!("Could not insert item {new_item:?}: {err}"))
+    with_db(conn, |conn| {
+        diesel::insert_into(items::table)
+            .values(&new_item)
+            .returning(Item::as_returning())
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not insert item {new_item:?}: {err}"))
+    })
+}
+
+/// Get the item with the given EAN, creating it if there is none yet. If an item with the same
+/// name exists, the EAN becomes an alias for it instead. The returned flag tells whether a new
+/// item was created.
+pub fn ensure_item(
+    barcode_ean: &str,
+    name: &str,
+    details: &ItemDetails,
+    conn: Option<&mut DbConnection>,
+) -> Result<(Item, bool)> {
+    use crate::schema::item_eans;
+    use crate::schema::items::dsl;
+
+    with_db(conn, |conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let existing = dsl::items
+                .filter(dsl::deleted_dt.is_null())
+                .filter(
+                    dsl::ean.eq(barcode_ean).or(dsl::id.eq_any(
+                        item_eans::table
+                            .filter(item_eans::ean.eq(barcode_ean))
+                            .select(item_eans::item_id),
+                    )),
+                )
+                .select(Item::as_select())
+                .first(conn)
+                .optional()?;
+            if let Some(item) = existing {
+                return Ok((item, false));
+            }
+            let same_name = dsl::items
+                .filter(lower(dsl::name).eq(lower(name)))
+                .filter(dsl::deleted_dt.is_null())
+                .select(Item::as_select())
+                .first(conn)
+                .optional()?;
+            if let Some(item) = same_name {
+                if item.ean.is_none() {
+                    anyhow::bail!("Name collision with custom item");
+                }
+                create_alias(barcode_ean, &item, Some(conn))?;
+                return Ok((item, false));
+            }
+            let item = create_item(Some(barcode_ean), name, details, Some(conn))?;
+            Ok((item, true))
+        })
+    })
+}
+
+pub fn set_min_stock(
+    item_id: i32,
+    min: Option<i32>,
+    conn: Option<&mut DbConnection>,
+) -> Result<Item> {
+    use crate::schema::items::dsl::*;
+
+    with_db(conn, |conn| {
+        diesel::update(items.find(item_id))
+            .set(min_stock.eq(min))
+            .returning(Item::as_returning())
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not update minimum stock: {err}"))
+    })
+}
+
+pub fn set_open_shelf_days(
+    item_id: i32,
+    days: Option<i32>,
+    conn: Option<&mut DbConnection>,
+) -> Result<Item> {
+    use crate::schema::items::dsl::*;
+
+    with_db(conn, |conn| {
+        diesel::update(items.find(item_id))
+            .set(open_shelf_days.eq(days))
+            .returning(Item::as_returning())
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not update open shelf life: {err}"))
+    })
+}
+
+pub fn set_note(item_id: i32, text: Option<&str>, conn: Option<&mut DbConnection>) -> Result<Item> {
+    use crate::schema::items::dsl::*;
+
+    with_db(conn, |conn| {
+        diesel::update(items.find(item_id))
+            .set(note.eq(text))
+            .returning(Item::as_returning())
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not update note: {err}"))
+    })
+}
+
+pub fn rename_item(item_id: i32, new_name: &str, conn: Option<&mut DbConnection>) -> Result<Item> {
+    use crate::schema::items::dsl::*;
+
+    with_db(conn, |conn| {
+        diesel::update(items.find(item_id))
+            .set(name.eq(new_name))
+            .returning(Item::as_returning())
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not rename item: {err}"))
+    })
 }
 
-pub fn create_alias(alias_ean: &str, item_ean: &str) -> Result<Alias> {
-    use crate::schema::aliases;
+/// Retire an item. Its stock is kept, but it is ignored when looking up items, so its EAN and
+/// name can be registered again.
+pub fn delete_item(item_id: i32, conn: Option<&mut DbConnection>) -> Result<Item> {
+    use crate::schema::items::dsl::*;
+
+    with_db(conn, |conn| {
+        diesel::update(items.find(item_id))
+            .set(deleted_dt.eq(now))
+            .returning(Item::as_returning())
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not delete item: {err}"))
+    })
+}
+
+/// Restore a deleted item. This fails if its EAN or name have been taken by another item since.
+/// Its extra EANs come back too, except those that another item uses as its EAN by now.
+pub fn undelete_item(item_id: i32, conn: Option<&mut DbConnection>) -> Result<Item> {
+    use crate::schema::{item_eans, items};
+
+    with_db(conn, |conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            diesel::delete(
+                item_eans::table
+                    .filter(item_eans::item_id.eq(item_id))
+                    .filter(
+                        item_eans::ean.nullable().eq_any(
+                            items::table
+                                .filter(items::deleted_dt.is_null())
+                                .select(items::ean),
+                        ),
+                    ),
+            )
+            .execute(conn)?;
+            let item = diesel::update(items::table.find(item_id))
+                .set(items::deleted_dt.eq(None::<DateTime<Local>>))
+                .returning(Item::as_returning())
+                .get_result(conn)?;
+            Ok(item)
+        })
+        .map_err(|err| anyhow::anyhow!("Could not restore item: {err}"))
+    })
+}
+
+/// Add an additional EAN to an item. If it belongs to a deleted item, it is taken over.
+pub fn create_alias(
+    alias_ean: &str,
+    item: &Item,
+    conn: Option<&mut DbConnection>,
+) -> Result<ItemEan> {
+    use crate::schema::{item_eans, items};
 
-    let new_alias = Alias {
+    let new_alias = ItemEan {
         ean: alias_ean.to_string(),
-        alias_for: item_ean.to_string(),
+        item_id: item.id,
     };
 
-    let conn = &mut connect_db()?;
-    diesel::insert_into(aliases::table)
-        .values(&new_alias)
-        .returning(Alias::as_returning())
-        .get_result(conn)
+    with_db(conn, |conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            diesel::delete(
+                item_eans::table
+                    .filter(item_eans::ean.eq(alias_ean))
+                    .filter(
+                        item_eans::item_id.eq_any(
+                            items::table
+                                .filter(items::deleted_dt.is_not_null())
+                                .select(items::id),
+                        ),
+                    ),
+            )
+            .execute(conn)?;
+            let alias = diesel::insert_into(item_eans::table)
+                .values(&new_alias)
+                .returning(ItemEan::as_returning())
+                .get_result(conn)?;
+            Ok(alias)
+        })
         .map_err(|err| anyhow::anyhow!("Could not insert alias {new_alias:?}: {err}"))
+    })
+}
+
+/// Delete an additional EAN, returning whether it existed.
+pub fn delete_alias(alias_ean: &str, conn: Option<&mut DbConnection>) -> Result<bool> {
+    use crate::schema::item_eans::dsl::*;
+
+    with_db(conn, |conn| {
+        diesel::delete(item_eans.find(alias_ean))
+            .execute(conn)
+            .map(|rows| rows > 0)
+            .map_err(|err| anyhow::anyhow!("Could not delete alias {alias_ean}: {err}"))
+    })
+}
+
+/// Get the additional EANs of an item.
+pub fn list_aliases_for(item: &Item) -> Result<Vec<ItemEan>> {
+    use crate::schema::item_eans::dsl::*;
+
+    with_connection(|conn| {
+        item_eans
+            .filter(item_id.eq(item.id))
+            .order(ean.asc())
+            .select(ItemEan::as_select())
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not load aliases for {}: {err}", item.name))
+    })
+}
+
+/// Merge the source item into the target item: Its stock and additional EANs are moved over,
+/// and its EAN becomes an additional EAN of the target (or the target's EAN, if the target is
+/// a custom item without one).
+pub fn merge_items(source_id: i32, target_id: i32, conn: Option<&mut DbConnection>) -> Result<()> {
+    use crate::schema::{item_eans, items, stock};
+
+    if source_id == target_id {
+        anyhow::bail!("Cannot merge an item into itself");
+    }
+    with_db(conn, |conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let source = items::table
+                .find(source_id)
+                .select(Item::as_select())
+                .first(conn)
+                .optional()?
+                .ok_or_else(|| anyhow::anyhow!("Source item {source_id} not found"))?;
+            let target = items::table
+                .find(target_id)
+                .select(Item::as_select())
+                .first(conn)
+                .optional()?
+                .ok_or_else(|| anyhow::anyhow!("Target item {target_id} not found"))?;
+            if let Some(source_ean) = &source.ean
+                && query_item_id_by_alias(conn, source_ean)?.is_some()
+            {
+                anyhow::bail!("EAN {source_ean} is already an alias");
+            }
+
+            diesel::update(stock::table.filter(stock::item_id.eq(source.id)))
+                .set(stock::item_id.eq(target.id))
+                .execute(conn)?;
+            // this must happen before deleting the source, which would delete its EANs as well
+            diesel::update(item_eans::table.filter(item_eans::item_id.eq(source.id)))
+                .set(item_eans::item_id.eq(target.id))
+                .execute(conn)?;
+            diesel::delete(items::table.find(source.id)).execute(conn)?;
+
+            let Some(source_ean) = source.ean else {
+                return Ok(());
+            };
+            if target.ean.is_some() {
+                create_alias(&source_ean, &target, Some(conn))?;
+            } else {
+                diesel::update(items::table.find(target.id))
+                    .set(items::ean.eq(source_ean.as_str()))
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+    })
 }
 
-pub fn add_to_stock(item: &Item, conn: Option<&mut PgConnection>) -> Result<Stock> {
+pub fn add_to_stock(
+    item: &Item,
+    quantity: i32,
+    expiry: Option<NaiveDate>,
+    conn: Option<&mut DbConnection>,
+) -> Result<Stock> {
     use crate::schema::stock;
-    use crate::schema::stock::dsl::*;
+    use crate::schema::stock::dsl;
 
-    let conn = match conn {
-        Some(conn) => conn,
-        None => &mut connect_db()?,
-    };
-    diesel::insert_into(stock::table)
-        .values(item_id.eq(item.id))
-        .returning(Stock::as_returning())
-        .get_result(conn)
-        .map_err(|err| {
+    with_db(conn, |conn| {
+        conn.transaction(|conn| {
+            let entry = diesel::insert_into(stock::table)
+                .values((
+                    dsl::item_id.eq(item.id),
+                    dsl::quantity.eq(quantity),
+                    dsl::expiry_dt.eq(expiry),
+                ))
+                .returning(Stock::as_returning())
+                .get_result(conn)?;
+            log_stock_action(conn, entry.id, StockAction::Add)?;
+            clear_shopping_list_entry(conn, item.id)?;
+            Ok(entry)
+        })
+        .map_err(|err: diesel::result::Error| {
             anyhow::anyhow!(
                 "Could not insert stock for {item_id:?}: {err}",
                 item_id = item.id
             )
         })
+    })
+}
+
+fn log_stock_action(
+    conn: &mut PgConnection,
+    stock_id: i32,
+    action: StockAction,
+) -> QueryResult<()> {
+    use crate::schema::stock_log::dsl;
+
+    diesel::insert_into(dsl::stock_log)
+        .values((dsl::stock_id.eq(stock_id), dsl::action.eq(action)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Delete the latest log entry of a stock entry when undoing it, returning when it happened.
+/// Nothing is deleted if the latest entry is for a different action.
+fn unlog_stock_action(
+    conn: &mut PgConnection,
+    stock_id: i32,
+    actions: &[StockAction],
+) -> QueryResult<Option<DateTime<Local>>> {
+    use crate::schema::stock_log::dsl;
+
+    let latest: Option<(i32, StockAction, DateTime<Local>)> = dsl::stock_log
+        .filter(dsl::stock_id.eq(stock_id))
+        .order(dsl::id.desc())
+        .select((dsl::id, dsl::action, dsl::occurred_dt))
+        .first(conn)
+        .optional()?;
+    let Some((log_id, _, occurred_dt)) = latest.filter(|(_, action, _)| actions.contains(action))
+    else {
+        return Ok(None);
+    };
+    diesel::delete(dsl::stock_log.find(log_id)).execute(conn)?;
+    Ok(Some(occurred_dt))
+}
+
+/// Get all recorded changes to the stock of an item, oldest first.
+pub fn query_stock_history(item_id: i32) -> Result<Vec<StockLogEntry>> {
+    use crate::schema::{stock, stock_log};
+
+    with_connection(|conn| {
+        stock_log::table
+            .inner_join(stock::table)
+            .filter(stock::item_id.eq(item_id))
+            .order((stock_log::occurred_dt.asc(), stock_log::id.asc()))
+            .select(StockLogEntry::as_select())
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not get stock history: {err}"))
+    })
+}
+
+/// Take a single unit out of a stock entry. The entry itself is only marked as removed
+/// once its last unit is gone.
+fn take_from_entry(conn: &mut PgConnection, entry: &Stock) -> QueryResult<()> {
+    use crate::schema::stock::dsl;
+
+    let target = diesel::update(dsl::stock.find(entry.id));
+    if entry.quantity > 1 {
+        target
+            .set(dsl::quantity.eq(dsl::quantity - 1))
+            .execute(conn)?;
+    } else if entry.opened_dt.is_some() {
+        // an opened unit is gone once it has been used up
+        target
+            .set((dsl::removed_dt.eq(now), dsl::remaining_fraction.eq(0.0)))
+            .execute(conn)?;
+    } else {
+        target.set(dsl::removed_dt.eq(now)).execute(conn)?;
+    }
+    Ok(())
 }
 
-pub fn remove_from_stock(item: &Item, stock_id: Option<i32>) -> Result<Result<()>> {
+/// Get a stock entry holding exactly one unit of the given entry, splitting it off into
+/// a new entry if necessary. This is needed before changing the state of a single unit,
+/// e.g. when opening it.
+fn split_from_entry(conn: &mut PgConnection, entry: &Stock) -> QueryResult<i32> {
     use crate::schema::stock;
     use crate::schema::stock::dsl;
 
-    let conn = &mut connect_db()?;
-    let rows = match stock_id {
-        None => sql_query(
-            r#"
-            with oldest as (
-                select id
-                from stock
-                where item_id = $1 and opened_dt is null and removed_dt is null
-                order by added_dt asc
-                limit 1
-            )
-            update stock s
-            set removed_dt = now()
-            from oldest
-            where s.id = oldest.id;
-        "#,
-        )
-        .bind::<Integer, _>(item.id)
-        .execute(conn)?,
-        Some(stock_id) => diesel::update(stock::table)
+    if entry.quantity == 1 {
+        return Ok(entry.id);
+    }
+    diesel::update(dsl::stock.find(entry.id))
+        .set(dsl::quantity.eq(dsl::quantity - 1))
+        .execute(conn)?;
+    diesel::insert_into(stock::table)
+        .values((
+            dsl::item_id.eq(entry.item_id),
+            dsl::added_dt.eq(entry.added_dt),
+            dsl::expiry_dt.eq(entry.expiry_dt),
+            dsl::location.eq(entry.location.as_deref()),
+        ))
+        .returning(dsl::id)
+        .get_result(conn)
+}
+
+pub fn remove_from_stock(
+    item: &Item,
+    stock_id: Option<i32>,
+    conn: Option<&mut DbConnection>,
+) -> Result<Result<i32>> {
+    use crate::schema::stock::dsl;
+
+    with_db(conn, |conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let entry = match stock_id {
+                None => {
+                    let query = dsl::stock
+                        .filter(
+                            dsl::item_id
+                                .eq(item.id)
+                                .and(dsl::opened_dt.is_null())
+                                .and(dsl::removed_dt.is_null()),
+                        )
+                        .select(Stock::as_select())
+                        .into_boxed();
+                    let query = match config().stock_order {
+                        StockOrder::Fifo => query.order(dsl::added_dt.asc()),
+                        StockOrder::Lifo => query.order(dsl::added_dt.desc()),
+                    };
+                    query.first(conn).optional()?
+                }
+                Some(stock_id) => dsl::stock
+                    .find(stock_id)
+                    .filter(dsl::item_id.eq(item.id).and(dsl::removed_dt.is_null()))
+                    .select(Stock::as_select())
+                    .first(conn)
+                    .optional()?,
+            };
+            let Some(entry) = entry else {
+                return Ok(Err(anyhow::anyhow!("item not in stock")));
+            };
+            take_from_entry(conn, &entry)?;
+            log_stock_action(conn, entry.id, StockAction::Remove)?;
+            update_shopping_list(conn, item)?;
+            Ok(Ok(entry.id))
+        })
+    })
+}
+
+/// Add or remove unopened units until the available stock matches the target count, returning
+/// the previous count.
+pub fn set_stock_count(item: &Item, target: i64, conn: Option<&mut DbConnection>) -> Result<i64> {
+    use crate::schema::stock::dsl;
+    use crate::schema::stock_log;
+
+    with_db(conn, |conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let available: Option<i64> = dsl::stock
+                .filter(
+                    dsl::item_id
+                        .eq(item.id)
+                        .and(dsl::removed_dt.is_null())
+                        .and(dsl::opened_dt.is_null()),
+                )
+                .select(sum(dsl::quantity))
+                .get_result(conn)?;
+            let available = available.unwrap_or(0);
+            if target > available {
+                let missing = i32::try_from(target - available)?;
+                add_to_stock(item, missing, None, Some(conn))?;
+            }
+            if target >= available {
+                return Ok(available);
+            }
+            let query = dsl::stock
+                .filter(
+                    dsl::item_id
+                        .eq(item.id)
+                        .and(dsl::opened_dt.is_null())
+                        .and(dsl::removed_dt.is_null()),
+                )
+                .select(Stock::as_select())
+                .into_boxed();
+            let query = match config().set_count_order {
+                StockOrder::Fifo => query.order(dsl::added_dt.asc()),
+                StockOrder::Lifo => query.order(dsl::added_dt.desc()),
+            };
+            let mut excess = available - target;
+            let mut emptied = Vec::new();
+            let mut removed_units = Vec::new();
+            for entry in query.load(conn)? {
+                if excess == 0 {
+                    break;
+                }
+                let taken = excess.min(entry.quantity.into());
+                excess -= taken;
+                removed_units.extend(std::iter::repeat_n(entry.id, usize::try_from(taken)?));
+                if taken == i64::from(entry.quantity) {
+                    emptied.push(entry.id);
+                } else {
+                    // only the last entry taken from can keep some of its units
+                    diesel::update(dsl::stock.find(entry.id))
+                        .set(dsl::quantity.eq(entry.quantity - i32::try_from(taken)?))
+                        .execute(conn)?;
+                }
+            }
+            // like `take_from_entry`, removed entries keep their last unit
+            diesel::update(dsl::stock.filter(dsl::id.eq_any(&emptied)))
+                .set((dsl::quantity.eq(1), dsl::removed_dt.eq(now)))
+                .execute(conn)?;
+            let log_rows: Vec<_> = removed_units
+                .into_iter()
+                .map(|stock_id| {
+                    (
+                        stock_log::stock_id.eq(stock_id),
+                        stock_log::action.eq(StockAction::Remove),
+                    )
+                })
+                .collect();
+            diesel::insert_into(stock_log::table)
+                .values(&log_rows)
+                .execute(conn)?;
+            update_shopping_list(conn, item)?;
+            Ok(available)
+        })
+    })
+}
+
+/// Open one unit of an item, or all of its available units if `open_whole_batch` is enabled.
+/// Returns the ids of all opened entries.
+pub fn open_from_stock(item: &Item, conn: Option<&mut DbConnection>) -> Result<Result<Vec<i32>>> {
+    use crate::schema::stock::dsl::*;
+    use diesel::dsl::{exists, select};
+
+    with_db(conn, |conn| {
+        if !config().allow_multiple_open {
+            let already_open = select(exists(
+                stock.filter(
+                    item_id
+                        .eq(item.id)
+                        .and(removed_dt.is_null())
+                        .and(opened_dt.is_not_null()),
+                ),
+            ))
+            .get_result::<bool>(conn)?;
+            if already_open {
+                return Ok(Err(anyhow::anyhow!("found open item in stock")));
+            }
+        }
+
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            if config().open_whole_batch {
+                let opened_ids: Vec<i32> = diesel::update(
+                    stock.filter(
+                        item_id
+                            .eq(item.id)
+                            .and(opened_dt.is_null())
+                            .and(removed_dt.is_null()),
+                    ),
+                )
+                .set(opened_dt.eq(now))
+                .returning(id)
+                .get_results(conn)?;
+                if opened_ids.is_empty() {
+                    return Ok(Err(anyhow::anyhow!("item not in stock")));
+                }
+                for &opened_id in &opened_ids {
+                    log_stock_action(conn, opened_id, StockAction::Open)?;
+                }
+                return Ok(Ok(opened_ids));
+            }
+            let query = stock
+                .filter(
+                    item_id
+                        .eq(item.id)
+                        .and(opened_dt.is_null())
+                        .and(removed_dt.is_null()),
+                )
+                .select(Stock::as_select())
+                .into_boxed();
+            let query = match config().stock_order {
+                StockOrder::Fifo => query.order(added_dt.asc()),
+                StockOrder::Lifo => query.order(added_dt.desc()),
+            };
+            let entry = query.first(conn).optional()?;
+            let Some(entry) = entry else {
+                return Ok(Err(anyhow::anyhow!("item not in stock")));
+            };
+            let single_id = split_from_entry(conn, &entry)?;
+            diesel::update(stock.find(single_id))
+                .set(opened_dt.eq(now))
+                .execute(conn)?;
+            log_stock_action(conn, single_id, StockAction::Open)?;
+            Ok(Ok(vec![single_id]))
+        })
+    })
+}
+
+pub fn finish_from_stock(item: &Item, conn: Option<&mut DbConnection>) -> Result<Result<i32>> {
+    use crate::schema::stock::dsl::*;
+    use diesel::dsl::{exists, select};
+
+    with_db(conn, |conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let entry = stock
+                .filter(
+                    item_id
+                        .eq(item.id)
+                        .and(opened_dt.is_not_null())
+                        .and(removed_dt.is_null()),
+                )
+                .order(opened_dt.asc())
+                .select(Stock::as_select())
+                .first(conn)
+                .optional()?;
+            let Some(entry) = entry else {
+                let any_available = select(exists(
+                    stock.filter(item_id.eq(item.id).and(removed_dt.is_null())),
+                ))
+                .get_result::<bool>(conn)?;
+                return Ok(Err(if any_available {
+                    anyhow::anyhow!("nothing opened, open one first")
+                } else {
+                    anyhow::anyhow!("item not in stock")
+                }));
+            };
+            take_from_entry(conn, &entry)?;
+            log_stock_action(conn, entry.id, StockAction::Finish)?;
+            update_shopping_list(conn, item)?;
+            Ok(Ok(entry.id))
+        })
+    })
+}
+
+/// Set how much is left of the opened unit that would be finished next.
+pub fn set_remaining_fraction(
+    item: &Item,
+    fraction: f32,
+    conn: Option<&mut DbConnection>,
+) -> Result<Result<i32>> {
+    use crate::schema::stock::dsl::*;
+
+    with_db(conn, |conn| {
+        let entry_id = stock
             .filter(
-                dsl::id
-                    .eq(stock_id)
-                    .and(dsl::item_id.eq(item.id))
-                    .and(dsl::removed_dt.is_null()),
+                item_id
+                    .eq(item.id)
+                    .and(opened_dt.is_not_null())
+                    .and(removed_dt.is_null()),
             )
-            .set(dsl::removed_dt.eq(now))
-            .execute(conn)?,
-    };
-    Ok(if rows > 0 {
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("item not in stock"))
+            .order(opened_dt.asc())
+            .select(id)
+            .first::<i32>(conn)
+            .optional()?;
+        let Some(entry_id) = entry_id else {
+            return Ok(Err(anyhow::anyhow!("nothing opened")));
+        };
+        diesel::update(stock.find(entry_id))
+            .set(remaining_fraction.eq(fraction))
+            .execute(conn)?;
+        Ok(Ok(entry_id))
     })
 }
 
-pub fn open_from_stock(item: &Item) -> Result<Result<()>> {
+/// Finish a specific opened unit, e.g. when several of them are open at the same time.
+pub fn finish_stock_by_id(
+    item: &Item,
+    stock_id: i32,
+    conn: Option<&mut DbConnection>,
+) -> Result<Result<i32>> {
     use crate::schema::stock::dsl::*;
-    use diesel::dsl::{exists, select};
 
-    let conn = &mut connect_db()?;
+    with_db(conn, |conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let entry = stock
+                .find(stock_id)
+                .filter(item_id.eq(item.id).and(removed_dt.is_null()))
+                .select(Stock::as_select())
+                .first(conn)
+                .optional()?;
+            let Some(entry) = entry else {
+                return Ok(Err(anyhow::anyhow!("item not in stock")));
+            };
+            if entry.opened_dt.is_none() {
+                return Ok(Err(anyhow::anyhow!("item not opened")));
+            }
+            take_from_entry(conn, &entry)?;
+            log_stock_action(conn, entry.id, StockAction::Finish)?;
+            update_shopping_list(conn, item)?;
+            Ok(Ok(entry.id))
+        })
+    })
+}
 
-    let already_open = select(exists(
-        stock.filter(
-            item_id
+/// Put an item on the shopping list if it has a minimum stock and no units are available
+/// anymore. Items that are already on the list are not added again.
+fn update_shopping_list(conn: &mut PgConnection, item: &Item) -> QueryResult<()> {
+    use crate::schema::shopping_list;
+    use crate::schema::stock::dsl;
+
+    if item.min_stock.is_none() {
+        return Ok(());
+    }
+    let available: Option<i64> = dsl::stock
+        .filter(
+            dsl::item_id
                 .eq(item.id)
-                .and(removed_dt.is_null())
-                .and(opened_dt.is_not_null()),
-        ),
-    ))
-    .get_result::<bool>(conn)?;
-    if already_open {
-        // TODO decide whether to allow having more than one open stock for an item
-        return Ok(Err(anyhow::anyhow!("found open item in stock")));
+                .and(dsl::removed_dt.is_null())
+                .and(dsl::opened_dt.is_null()),
+        )
+        .select(sum(dsl::quantity))
+        .get_result(conn)?;
+    if available.unwrap_or(0) > 0 {
+        return Ok(());
     }
+    diesel::insert_into(shopping_list::table)
+        .values(shopping_list::item_id.eq(item.id))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(())
+}
 
-    let rows = sql_query(
-        r#"
-        with oldest as (
-            select id
-            from stock
-            where item_id = $1 and opened_dt is null and removed_dt is null
-            order by added_dt asc
-            limit 1
-        )
-        update stock s
-        set opened_dt = now()
-        from oldest
-        where s.id = oldest.id;
-        "#,
+fn clear_shopping_list_entry(conn: &mut PgConnection, item_id: i32) -> QueryResult<bool> {
+    use crate::schema::shopping_list::dsl;
+
+    diesel::update(
+        dsl::shopping_list.filter(dsl::item_id.eq(item_id).and(dsl::removed_dt.is_null())),
     )
-    .bind::<Integer, _>(item.id)
-    .execute(conn)?;
-    Ok(if rows > 0 {
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("item not in stock"))
+    .set(dsl::removed_dt.eq(now))
+    .execute(conn)
+    .map(|count| count > 0)
+}
+
+/// Get all items on the shopping list, oldest first.
+pub fn query_shopping_list() -> Result<Vec<(ShoppingListEntry, Item)>> {
+    use crate::schema::{items, shopping_list};
+
+    with_connection(|conn| {
+        shopping_list::table
+            .inner_join(items::table)
+            .filter(shopping_list::removed_dt.is_null())
+            .filter(items::deleted_dt.is_null())
+            .order(shopping_list::added_dt.asc())
+            .select((ShoppingListEntry::as_select(), Item::as_select()))
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not query shopping list: {err}"))
     })
 }
 
-pub fn finish_from_stock(item: &Item) -> Result<Result<()>> {
-    let conn = &mut connect_db()?;
+/// Take an item off the shopping list, e.g. after it has been bought. This also happens
+/// automatically when it is added to the stock again.
+pub fn remove_from_shopping_list(item_id: i32, conn: Option<&mut DbConnection>) -> Result<bool> {
+    with_db(conn, |conn| {
+        clear_shopping_list_entry(conn, item_id)
+            .map_err(|err| anyhow::anyhow!("Could not update shopping list: {err}"))
+    })
+}
 
-    let rows = sql_query(
-        r#"
-        with oldest as (
-            select id
-            from stock
-            where item_id = $1 and opened_dt is not null and removed_dt is null
-            order by opened_dt asc
-            limit 1
-        )
-        update stock s
-        set removed_dt = now()
-        from oldest
-        where s.id = oldest.id;
-        "#,
-    )
-    .bind::<Integer, _>(item.id)
-    .execute(conn)?;
-    Ok(if rows > 0 {
+/// Move a stock entry (with all its units) to a different storage location.
+pub fn update_stock_location(
+    stock_id: i32,
+    new_location: Option<&str>,
+    conn: Option<&mut DbConnection>,
+) -> Result<Stock> {
+    use crate::schema::stock::dsl::*;
+
+    with_db(conn, |conn| {
+        diesel::update(stock.find(stock_id))
+            .set(location.eq(new_location))
+            .returning(Stock::as_returning())
+            .get_result(conn)
+            .map_err(|err| anyhow::anyhow!("Could not update stock location: {err}"))
+    })
+}
+
+/// Revert adding a stock entry, unless any of its units have been used since. Its log entry
+/// goes away with it, and the item is put back on the shopping list if adding it took it off.
+pub fn undo_add(stock_id: i32, conn: Option<&mut DbConnection>) -> Result<Result<()>> {
+    use crate::schema::{shopping_list, stock, stock_log};
+    use diesel::dsl::{exists, select};
+
+    with_db(conn, |conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let entry = stock::table
+                .find(stock_id)
+                .select(Stock::as_select())
+                .first(conn)
+                .optional()?;
+            let Some(entry) = entry else {
+                return Ok(Err(anyhow::anyhow!("stock not found")));
+            };
+            let used = select(exists(
+                stock_log::table.filter(
+                    stock_log::stock_id
+                        .eq(entry.id)
+                        .and(stock_log::action.ne(StockAction::Add)),
+                ),
+            ))
+            .get_result::<bool>(conn)?;
+            // units split off e.g. when opening one keep the time they were added
+            let split = select(exists(
+                stock::table.filter(
+                    stock::item_id
+                        .eq(entry.item_id)
+                        .and(stock::added_dt.eq(entry.added_dt))
+                        .and(stock::id.ne(entry.id)),
+                ),
+            ))
+            .get_result::<bool>(conn)?;
+            if used || split || entry.opened_dt.is_some() || entry.removed_dt.is_some() {
+                return Ok(Err(anyhow::anyhow!(
+                    "stock has been used since, remove it instead"
+                )));
+            }
+            diesel::delete(stock::table.find(entry.id)).execute(conn)?;
+            let already_listed = select(exists(
+                shopping_list::table.filter(
+                    shopping_list::item_id
+                        .eq(entry.item_id)
+                        .and(shopping_list::removed_dt.is_null()),
+                ),
+            ))
+            .get_result::<bool>(conn)?;
+            if !already_listed {
+                let cleared: Option<i32> = shopping_list::table
+                    .filter(shopping_list::item_id.eq(entry.item_id))
+                    .filter(shopping_list::removed_dt.ge(entry.added_dt))
+                    .order(shopping_list::removed_dt.desc())
+                    .select(shopping_list::id)
+                    .first(conn)
+                    .optional()?;
+                if let Some(cleared) = cleared {
+                    diesel::update(shopping_list::table.find(cleared))
+                        .set(shopping_list::removed_dt.eq(None::<DateTime<Local>>))
+                        .execute(conn)?;
+                }
+            }
+            Ok(Ok(()))
+        })
+    })
+}
+
+/// Revert removing or finishing a single unit of a stock entry, including the shopping list
+/// entry this may have created.
+pub fn undo_remove(stock_id: i32, conn: Option<&mut DbConnection>) -> Result<Result<()>> {
+    use crate::schema::{shopping_list, stock};
+
+    with_db(conn, |conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let entry = stock::table
+                .find(stock_id)
+                .select(Stock::as_select())
+                .first(conn)
+                .optional()?;
+            let Some(entry) = entry else {
+                return Ok(Err(anyhow::anyhow!("stock not found")));
+            };
+            let target = diesel::update(stock::table.find(entry.id));
+            if entry.removed_dt.is_some() {
+                target
+                    .set((
+                        stock::removed_dt.eq(None::<DateTime<Local>>),
+                        stock::remaining_fraction.eq(None::<f32>),
+                    ))
+                    .execute(conn)?;
+            } else {
+                // only some units of the entry had been taken out
+                target
+                    .set(stock::quantity.eq(stock::quantity + 1))
+                    .execute(conn)?;
+            }
+            let removed_at =
+                unlog_stock_action(conn, entry.id, &[StockAction::Remove, StockAction::Finish])?;
+            if let Some(removed_at) = removed_at {
+                diesel::delete(
+                    shopping_list::table.filter(
+                        shopping_list::item_id
+                            .eq(entry.item_id)
+                            .and(shopping_list::removed_dt.is_null())
+                            .and(shopping_list::added_dt.ge(removed_at)),
+                    ),
+                )
+                .execute(conn)?;
+            }
+            Ok(Ok(()))
+        })
+    })
+}
+
+pub fn undo_open(stock_id: i32, conn: Option<&mut DbConnection>) -> Result<Result<()>> {
+    use crate::schema::stock::dsl::*;
+
+    with_db(conn, |conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let rows = diesel::update(stock.find(stock_id))
+                .filter(opened_dt.is_not_null().and(removed_dt.is_null()))
+                .set((
+                    opened_dt.eq(None::<DateTime<Local>>),
+                    remaining_fraction.eq(None::<f32>),
+                ))
+                .execute(conn)?;
+            if rows == 0 {
+                return Ok(Err(anyhow::anyhow!("stock not open anymore")));
+            }
+            unlog_stock_action(conn, stock_id, &[StockAction::Open])?;
+            Ok(Ok(()))
+        })
+    })
+}
+
+pub fn query_expiring_soon(days: i64) -> Result<Vec<(Item, Stock)>> {
+    use crate::schema::{items, stock};
+
+    with_connection(|conn| {
+        let cutoff = Local::now().date_naive() + TimeDelta::days(days);
+        stock::table
+            .inner_join(items::table)
+            .filter(stock::removed_dt.is_null())
+            .filter(stock::expiry_dt.le(cutoff))
+            .order(stock::expiry_dt.asc())
+            .select((Item::as_select(), Stock::as_select()))
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not query expiring stock: {err}"))
+    })
+}
+
+/// Get all opened stock that has been open for longer than its item keeps once opened,
+/// oldest first.
+pub fn query_overdue_open() -> Result<Vec<(Item, Stock)>> {
+    use crate::schema::{items, stock};
+
+    with_connection(|conn| {
+        let opened: Vec<(Item, Stock)> = stock::table
+            .inner_join(items::table)
+            .filter(stock::removed_dt.is_null())
+            .filter(stock::opened_dt.is_not_null())
+            .filter(items::open_shelf_days.is_not_null())
+            .order(stock::opened_dt.asc())
+            .select((Item::as_select(), Stock::as_select()))
+            .load(conn)
+            .map_err(|err| anyhow::anyhow!("Could not query opened stock: {err}"))?;
+        let now = Local::now();
+        Ok(opened
+            .into_iter()
+            .filter(|(item, stock)| {
+                let opened = stock.opened_dt.expect("only opened stock is queried");
+                let days = item
+                    .open_shelf_days
+                    .expect("only items with open shelf life are queried");
+                opened + TimeDelta::days(days.into()) < now
+            })
+            .collect())
+    })
+}
+
+/// Get a cached openfoodfacts lookup result unless it is older than `max_age`.
+pub fn query_off_cache(barcode_ean: &str, max_age: TimeDelta) -> Result<Option<OffCacheEntry>> {
+    use crate::schema::off_cache::dsl::*;
+
+    with_connection(|conn| {
+        off_cache
+            .find(barcode_ean)
+            .filter(fetched_dt.gt(Local::now() - max_age))
+            .select(OffCacheEntry::as_select())
+            .first(conn)
+            .optional()
+            .map_err(|err| anyhow::anyhow!("Could not load cached lookup for {barcode_ean}: {err}"))
+    })
+}
+
+/// Cache an openfoodfacts lookup result. A missing name means that the product is not known.
+pub fn store_off_cache(
+    barcode_ean: &str,
+    product_name: Option<&str>,
+    details: &ItemDetails,
+    conn: Option<&mut DbConnection>,
+) -> Result<()> {
+    use crate::schema::off_cache::dsl::*;
+
+    with_db(conn, |conn| {
+        diesel::insert_into(off_cache)
+            .values((
+                ean.eq(barcode_ean),
+                name.eq(product_name),
+                brand.eq(details.brand.as_deref()),
+                quantity.eq(details.quantity.as_deref()),
+                category.eq(details.category.as_deref()),
+            ))
+            .on_conflict(ean)
+            .do_update()
+            .set((
+                name.eq(product_name),
+                brand.eq(details.brand.as_deref()),
+                quantity.eq(details.quantity.as_deref()),
+                category.eq(details.category.as_deref()),
+                fetched_dt.eq(now),
+            ))
+            .execute(conn)
+            .map_err(|err| anyhow::anyhow!("Could not cache lookup for {barcode_ean}: {err}"))?;
         Ok(())
-    } else {
-        Err(anyhow::anyhow!("item not in stock or not opened"))
     })
 }
+
+pub fn export_backup() -> Result<Backup> {
+    use crate::schema::{item_eans, items, shopping_list, stock, stock_log};
+
+    with_connection(|conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            Ok(Backup {
+                items: items::table
+                    .order(items::id)
+                    .select(Item::as_select())
+                    .load(conn)?,
+                eans: item_eans::table
+                    .order(item_eans::ean)
+                    .select(ItemEan::as_select())
+                    .load(conn)?,
+                aliases: Vec::new(),
+                stock: stock::table
+                    .order(stock::id)
+                    .select(Stock::as_select())
+                    .load(conn)?,
+                stock_log: stock_log::table
+                    .order(stock_log::id)
+                    .select(StockLogEntry::as_select())
+                    .load(conn)?,
+                shopping_list: shopping_list::table
+                    .order(shopping_list::id)
+                    .select(ShoppingListEntry::as_select())
+                    .load(conn)?,
+            })
+        })
+        .map_err(|err| anyhow::anyhow!("Could not export data: {err}"))
+    })
+}
+
+/// Get the additional EANs from a backup, including those from aliases in old backups.
+pub fn backup_eans(backup: &Backup) -> Vec<ItemEan> {
+    let legacy = backup.aliases.iter().filter_map(|alias| {
+        let item = backup
+            .items
+            .iter()
+            .filter(|item| item.ean.as_deref() == Some(alias.alias_for.as_str()))
+            .min_by_key(|item| item.deleted_dt.is_some())?;
+        Some(ItemEan {
+            ean: alias.ean.clone(),
+            item_id: item.id,
+        })
+    });
+    legacy.chain(backup.eans.iter().cloned()).collect()
+}
+
+/// How many rows are inserted at once when restoring a backup, which keeps the number of bind
+/// parameters below the limit of Postgres.
+const RESTORE_CHUNK_SIZE: usize = 1000;
+
+/// Import a backup. When restoring, the database must be empty and all ids are kept. When
+/// merging, items matching an existing item by EAN or name are mapped to it, and all other
+/// rows get new ids. Stock entries and shopping list entries that such an item already has
+/// (judging by when they were added) are skipped, so merging the same backup twice does not
+/// duplicate them.
+pub fn import_backup(backup: &Backup, merge: bool, conn: Option<&mut DbConnection>) -> Result<()> {
+    use crate::schema::{item_eans, items, shopping_list, stock, stock_log};
+    use diesel::dsl::{exists, select};
+
+    with_db(conn, |conn| {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            if !merge {
+                let existing: i64 = items::table.count().get_result(conn)?;
+                if existing > 0 {
+                    anyhow::bail!("Database is not empty, only merging is possible");
+                }
+                for chunk in backup.items.chunks(RESTORE_CHUNK_SIZE) {
+                    diesel::insert_into(items::table)
+                        .values(chunk)
+                        .execute(conn)?;
+                }
+                for chunk in backup_eans(backup).chunks(RESTORE_CHUNK_SIZE) {
+                    diesel::insert_into(item_eans::table)
+                        .values(chunk)
+                        .execute(conn)?;
+                }
+                for chunk in backup.stock.chunks(RESTORE_CHUNK_SIZE) {
+                    diesel::insert_into(stock::table)
+                        .values(chunk)
+                        .execute(conn)?;
+                }
+                for chunk in backup.stock_log.chunks(RESTORE_CHUNK_SIZE) {
+                    diesel::insert_into(stock_log::table)
+                        .values(chunk)
+                        .execute(conn)?;
+                }
+                for chunk in backup.shopping_list.chunks(RESTORE_CHUNK_SIZE) {
+                    diesel::insert_into(shopping_list::table)
+                        .values(chunk)
+                        .execute(conn)?;
+                }
+                // the ids were set explicitly, so the sequences do not know about them yet
+                for table in ["items", "stock", "stock_log", "shopping_list"] {
+                    diesel::sql_query(format!(
+                        "select setval('{table}_id_seq', \
+                         (select coalesce(max(id), 0) + 1 from {table}), false)"
+                    ))
+                    .execute(conn)?;
+                }
+                return Ok(());
+            }
+
+            let mut item_ids = HashMap::new();
+            for item in &backup.items {
+                let existing = if item.deleted_dt.is_some() {
+                    None
+                } else {
+                    items::table
+                        .filter(items::deleted_dt.is_null())
+                        .filter(
+                            items::ean
+                                .eq(item.ean.as_deref())
+                                .or(lower(items::name).eq(lower(&item.name))),
+                        )
+                        .select(items::id)
+                        .first(conn)
+                        .optional()?
+                };
+                let id = match existing {
+                    Some(id) => id,
+                    None => diesel::insert_into(items::table)
+                        .values((
+                            items::name.eq(&item.name),
+                            items::kind.eq(&item.kind),
+                            items::ean.eq(item.ean.as_deref()),
+                            items::min_stock.eq(item.min_stock),
+                            items::brand.eq(item.brand.as_deref()),
+                            items::quantity.eq(item.quantity.as_deref()),
+                            items::category.eq(item.category.as_deref()),
+                            items::deleted_dt.eq(item.deleted_dt),
+                            items::open_shelf_days.eq(item.open_shelf_days),
+                            items::note.eq(item.note.as_deref()),
+                        ))
+                        .returning(items::id)
+                        .get_result(conn)?,
+                };
+                item_ids.insert(item.id, id);
+            }
+            for item_ean in backup_eans(backup) {
+                let item_id = *item_ids
+                    .get(&item_ean.item_id)
+                    .ok_or_else(|| anyhow::anyhow!("EAN {} has an unknown item", item_ean.ean))?;
+                diesel::insert_into(item_eans::table)
+                    .values((
+                        item_eans::ean.eq(&item_ean.ean),
+                        item_eans::item_id.eq(item_id),
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+            }
+            // units split off a batch share its added_dt, so only compare against stock that
+            // existed before the import
+            let mut new_stock = Vec::new();
+            for entry in &backup.stock {
+                let item_id = *item_ids
+                    .get(&entry.item_id)
+                    .ok_or_else(|| anyhow::anyhow!("Stock {} has an unknown item", entry.id))?;
+                let known = select(exists(
+                    stock::table.filter(
+                        stock::item_id
+                            .eq(item_id)
+                            .and(stock::added_dt.eq(entry.added_dt)),
+                    ),
+                ))
+                .get_result::<bool>(conn)?;
+                if !known {
+                    new_stock.push((entry, item_id));
+                }
+            }
+            // only stock that is new gets its history imported
+            let mut stock_ids = HashMap::new();
+            for (entry, item_id) in new_stock {
+                let id = diesel::insert_into(stock::table)
+                    .values((
+                        stock::item_id.eq(item_id),
+                        stock::added_dt.eq(entry.added_dt),
+                        stock::opened_dt.eq(entry.opened_dt),
+                        stock::removed_dt.eq(entry.removed_dt),
+                        stock::expiry_dt.eq(entry.expiry_dt),
+                        stock::quantity.eq(entry.quantity),
+                        stock::location.eq(entry.location.as_deref()),
+                        stock::remaining_fraction.eq(entry.remaining_fraction),
+                    ))
+                    .returning(stock::id)
+                    .get_result::<i32>(conn)?;
+                stock_ids.insert(entry.id, id);
+            }
+            for log_entry in &backup.stock_log {
+                let Some(&stock_id) = stock_ids.get(&log_entry.stock_id) else {
+                    continue;
+                };
+                diesel::insert_into(stock_log::table)
+                    .values((
+                        stock_log::stock_id.eq(stock_id),
+                        stock_log::action.eq(log_entry.action),
+                        stock_log::occurred_dt.eq(log_entry.occurred_dt),
+                    ))
+                    .execute(conn)?;
+            }
+            for list_entry in &backup.shopping_list {
+                let item_id = *item_ids.get(&list_entry.item_id).ok_or_else(|| {
+                    anyhow::anyhow!("Shopping list entry {} has an unknown item", list_entry.id)
+                })?;
+                let known = select(exists(
+                    shopping_list::table.filter(
+                        shopping_list::item_id
+                            .eq(item_id)
+                            .and(shopping_list::added_dt.eq(list_entry.added_dt)),
+                    ),
+                ))
+                .get_result::<bool>(conn)?;
+                if known {
+                    continue;
+                }
+                // an item that is on the list already cannot be put on it a second time
+                diesel::insert_into(shopping_list::table)
+                    .values((
+                        shopping_list::item_id.eq(item_id),
+                        shopping_list::added_dt.eq(list_entry.added_dt),
+                        shopping_list::removed_dt.eq(list_entry.removed_dt),
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+        .map_err(|err| anyhow::anyhow!("Could not import data: {err}"))
+    })
+}
+
+/// A problem in the data that cannot be prevented by database constraints.
+#[derive(Debug)]
+pub enum Inconsistency {
+    /// The EAN belongs to an item but is also an alias, so lookups are ambiguous.
+    AliasedItemEan(String),
+    /// The item has been deleted but still has stock.
+    DeletedItemInStock(Item),
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::AliasedItemEan(ean) => {
+                write!(f, "EAN {ean} is both an item and an alias")
+            }
+            Inconsistency::DeletedItemInStock(item) => {
+                write!(
+                    f,
+                    "deleted item {} ({}) is still in stock",
+                    item.name, item.id
+                )
+            }
+        }
+    }
+}
+
+pub fn check_consistency() -> Result<Vec<Inconsistency>> {
+    use crate::schema::{item_eans, items, stock};
+
+    with_connection(|conn| {
+        // extra EANs of deleted items are ignored by lookups, so they cannot be ambiguous
+        let aliased: Vec<String> = item_eans::table
+            .inner_join(
+                items::table.on(items::ean
+                    .eq(item_eans::ean.nullable())
+                    .and(items::deleted_dt.is_null())),
+            )
+            .filter(
+                item_eans::item_id.eq_any(
+                    items::table
+                        .filter(items::deleted_dt.is_null())
+                        .select(items::id),
+                ),
+            )
+            .select(item_eans::ean)
+            .order(item_eans::ean)
+            .load(conn)?;
+        let deleted_in_stock: Vec<Item> = items::table
+            .inner_join(stock::table)
+            .filter(items::deleted_dt.is_not_null())
+            .filter(stock::removed_dt.is_null())
+            .select(Item::as_select())
+            .distinct()
+            .load(conn)?;
+        Ok(aliased
+            .into_iter()
+            .map(Inconsistency::AliasedItemEan)
+            .chain(
+                deleted_in_stock
+                    .into_iter()
+                    .map(Inconsistency::DeletedItemInStock),
+            )
+            .collect())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use diesel::r2d2::TestCustomizer;
+
+    /// Get a connection to the database in `TEST_DATABASE_URL`. It is inside a transaction that
+    /// is never committed, so nothing the tests do is persisted.
+    fn test_connection() -> DbConnection {
+        let url = env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+        let pool = Pool::builder()
+            .max_size(1)
+            .connection_customizer(Box::new(TestCustomizer))
+            .build(ConnectionManager::new(url))
+            .expect("Could not connect to the test database");
+        let mut conn = pool.get().expect("Could not get test database connection");
+        conn.run_pending_migrations(MIGRATIONS)
+            .expect("Could not run migrations");
+        conn
+    }
+
+    fn create_test_item(conn: &mut DbConnection) -> Item {
+        create_item(None, "Test item", &ItemDetails::default(), Some(conn)).unwrap()
+    }
+
+    /// Add a single unit that was bought on the given day. `add_to_stock` cannot be used for
+    /// this since `now()` is the same for the whole transaction.
+    fn add_test_stock(conn: &mut DbConnection, item: &Item, day: u32) -> Stock {
+        use crate::schema::stock;
+
+        diesel::insert_into(stock::table)
+            .values((
+                stock::item_id.eq(item.id),
+                stock::added_dt.eq(Local.with_ymd_and_hms(2026, 1, day, 12, 0, 0).unwrap()),
+            ))
+            .returning(Stock::as_returning())
+            .get_result(conn)
+            .unwrap()
+    }
+
+    fn load_stock(conn: &mut DbConnection, stock_id: i32) -> Stock {
+        use crate::schema::stock::dsl;
+
+        dsl::stock
+            .find(stock_id)
+            .select(Stock::as_select())
+            .first(conn)
+            .unwrap()
+    }
+
+    fn is_on_shopping_list(conn: &mut DbConnection, item: &Item) -> bool {
+        use crate::schema::shopping_list::dsl;
+        use diesel::dsl::{exists, select};
+
+        select(exists(dsl::shopping_list.filter(
+            dsl::item_id.eq(item.id).and(dsl::removed_dt.is_null()),
+        )))
+        .get_result(conn)
+        .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs a test database in TEST_DATABASE_URL"]
+    fn ensure_item_is_idempotent() {
+        let conn = &mut test_connection();
+        let details = ItemDetails::default();
+        let (item, created) =
+            ensure_item("4006381333931", "Pen", &details, Some(&mut *conn)).unwrap();
+        assert!(created);
+        let (again, created) =
+            ensure_item("4006381333931", "Pen", &details, Some(&mut *conn)).unwrap();
+        assert!(!created);
+        assert_eq!(again.id, item.id);
+        // a different EAN with the same name becomes an alias
+        let (aliased, created) =
+            ensure_item("4006381333948", "pen", &details, Some(&mut *conn)).unwrap();
+        assert!(!created);
+        assert_eq!(aliased.id, item.id);
+        let (again, created) =
+            ensure_item("4006381333948", "Other", &details, Some(&mut *conn)).unwrap();
+        assert!(!created);
+        assert_eq!(again.id, item.id);
+    }
+
+    #[test]
+    #[ignore = "needs a test database in TEST_DATABASE_URL"]
+    fn remove_takes_oldest_first() {
+        let conn = &mut test_connection();
+        let item = create_test_item(conn);
+        let newer = add_test_stock(conn, &item, 2);
+        let older = add_test_stock(conn, &item, 1);
+
+        let removed = remove_from_stock(&item, None, Some(&mut *conn))
+            .unwrap()
+            .unwrap();
+        assert_eq!(removed, older.id);
+        let removed = remove_from_stock(&item, None, Some(&mut *conn))
+            .unwrap()
+            .unwrap();
+        assert_eq!(removed, newer.id);
+        assert!(
+            remove_from_stock(&item, None, Some(&mut *conn))
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a test database in TEST_DATABASE_URL"]
+    fn remove_skips_opened_stock() {
+        let conn = &mut test_connection();
+        let item = create_test_item(conn);
+        let older = add_test_stock(conn, &item, 1);
+        let newer = add_test_stock(conn, &item, 2);
+
+        let opened = open_from_stock(&item, Some(&mut *conn)).unwrap().unwrap()[0];
+        assert_eq!(opened, older.id);
+        let removed = remove_from_stock(&item, None, Some(&mut *conn))
+            .unwrap()
+            .unwrap();
+        assert_eq!(removed, newer.id);
+    }
+
+    #[test]
+    #[ignore = "needs a test database in TEST_DATABASE_URL"]
+    fn open_splits_entry() {
+        let conn = &mut test_connection();
+        let item = create_test_item(conn);
+        let entry = add_to_stock(&item, 3, None, Some(&mut *conn)).unwrap();
+
+        let opened = open_from_stock(&item, Some(&mut *conn)).unwrap().unwrap()[0];
+        assert_ne!(opened, entry.id);
+        assert_eq!(load_stock(conn, entry.id).quantity, 2);
+        let opened = load_stock(conn, opened);
+        assert_eq!(opened.quantity, 1);
+        assert!(opened.opened_dt.is_some());
+    }
+
+    #[test]
+    #[ignore = "needs a test database in TEST_DATABASE_URL"]
+    fn finish_needs_opened_stock() {
+        let conn = &mut test_connection();
+        let item = create_test_item(conn);
+        assert!(finish_from_stock(&item, Some(&mut *conn)).unwrap().is_err());
+
+        let entry = add_test_stock(conn, &item, 1);
+        assert!(finish_from_stock(&item, Some(&mut *conn)).unwrap().is_err());
+
+        open_from_stock(&item, Some(&mut *conn)).unwrap().unwrap();
+        let finished = finish_from_stock(&item, Some(&mut *conn)).unwrap().unwrap();
+        assert_eq!(finished, entry.id);
+        assert!(load_stock(conn, entry.id).removed_dt.is_some());
+    }
+
+    #[test]
+    #[ignore = "needs a test database in TEST_DATABASE_URL"]
+    fn undo_remove_reverts_log_and_shopping_list() {
+        let conn = &mut test_connection();
+        let item = create_test_item(conn);
+        let item = set_min_stock(item.id, Some(1), Some(&mut *conn)).unwrap();
+        let entry = add_to_stock(&item, 1, None, Some(&mut *conn)).unwrap();
+
+        remove_from_stock(&item, None, Some(&mut *conn))
+            .unwrap()
+            .unwrap();
+        assert!(is_on_shopping_list(conn, &item));
+        undo_remove(entry.id, Some(&mut *conn)).unwrap().unwrap();
+        assert!(load_stock(conn, entry.id).removed_dt.is_none());
+        assert!(!is_on_shopping_list(conn, &item));
+        let actions: Vec<StockAction> = {
+            use crate::schema::stock_log::dsl;
+
+            dsl::stock_log
+                .filter(dsl::stock_id.eq(entry.id))
+                .select(dsl::action)
+                .load(conn)
+                .unwrap()
+        };
+        assert_eq!(actions, [StockAction::Add]);
+    }
+
+    #[test]
+    #[ignore = "needs a test database in TEST_DATABASE_URL"]
+    fn lowering_stock_count_updates_shopping_list() {
+        let conn = &mut test_connection();
+        let item = create_test_item(conn);
+        let item = set_min_stock(item.id, Some(1), Some(&mut *conn)).unwrap();
+        let older = add_test_stock(conn, &item, 1);
+        let newer = add_to_stock(&item, 2, None, Some(&mut *conn)).unwrap();
+
+        assert_eq!(set_stock_count(&item, 1, Some(&mut *conn)).unwrap(), 3);
+        assert!(load_stock(conn, older.id).removed_dt.is_some());
+        assert_eq!(load_stock(conn, newer.id).quantity, 1);
+        assert!(!is_on_shopping_list(conn, &item));
+        assert_eq!(set_stock_count(&item, 0, Some(&mut *conn)).unwrap(), 1);
+        assert!(load_stock(conn, newer.id).removed_dt.is_some());
+        assert!(is_on_shopping_list(conn, &item));
+    }
+
+    #[test]
+    #[ignore = "needs a test database in TEST_DATABASE_URL"]
+    fn undo_add_refuses_used_stock() {
+        let conn = &mut test_connection();
+        let item = create_test_item(conn);
+        let entry = add_to_stock(&item, 3, None, Some(&mut *conn)).unwrap();
+
+        let opened = open_from_stock(&item, Some(&mut *conn)).unwrap().unwrap()[0];
+        undo_open(opened, Some(&mut *conn)).unwrap().unwrap();
+        assert!(undo_add(entry.id, Some(&mut *conn)).unwrap().is_err());
+        assert_eq!(load_stock(conn, entry.id).quantity, 2);
+
+        let unused = add_test_stock(conn, &item, 1);
+        undo_add(unused.id, Some(&mut *conn)).unwrap().unwrap();
+    }
+}
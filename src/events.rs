@@ -0,0 +1,121 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::io::{ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use tracing::{error, info, warn};
+
+static EVENTS: OnceLock<Sender<String>> = OnceLock::new();
+
+/// How much a client may fall behind before it is dropped.
+const MAX_PENDING: usize = 64 * 1024;
+
+/// A stock change, published as one line of JSON.
+#[derive(Debug, Serialize)]
+pub struct Event<'a> {
+    pub item_id: i32,
+    pub item: &'a str,
+    /// What happened, e.g. `added`, `opened`, `counted` or `undone`.
+    pub action: &'a str,
+    pub timestamp: DateTime<Local>,
+    /// How many units are still available afterwards, if known.
+    pub remaining: Option<i64>,
+}
+
+/// Accept clients on a Unix socket, which then receive all published events.
+pub fn serve(path: &Path) -> Result<()> {
+    // a socket left behind by a previous run would make binding fail
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => anyhow::bail!("Could not remove old socket {}: {err}", path.display()),
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|err| anyhow::anyhow!("Could not listen on {}: {err}", path.display()))?;
+    info!("publishing events on {}", path.display());
+
+    let clients = Arc::new(Mutex::new(Vec::<Client>::new()));
+    let (tx, rx) = mpsc::channel::<String>();
+    EVENTS
+        .set(tx)
+        .map_err(|_| anyhow::anyhow!("Event socket is already running"))?;
+    let writer_clients = Arc::clone(&clients);
+    thread::spawn(move || {
+        for line in rx {
+            let mut clients = writer_clients.lock().expect("Client list is not poisoned");
+            // clients that went away or do not keep up are dropped
+            clients.retain_mut(|client| client.send(&line));
+        }
+    });
+
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(err) => {
+                warn!("accepting event client failed: {err}");
+                continue;
+            }
+        };
+        // a stuck client must not block the events for everyone else
+        if let Err(err) = client.set_nonblocking(true) {
+            warn!("setting up event client failed: {err}");
+            continue;
+        }
+        clients
+            .lock()
+            .expect("Client list is not poisoned")
+            .push(Client {
+                stream: client,
+                pending: Vec::new(),
+            });
+    }
+    Ok(())
+}
+
+/// A connected client and the part of the events it was not ready to receive yet.
+struct Client {
+    stream: UnixStream,
+    pending: Vec<u8>,
+}
+
+impl Client {
+    /// Queue a line and write as much as the client accepts. Returns whether to keep the client.
+    fn send(&mut self, line: &str) -> bool {
+        if self.pending.len() + line.len() > MAX_PENDING {
+            return false;
+        }
+        // a line is only ever written after the rest of the previous one, so none get mangled
+        self.pending.extend_from_slice(line.as_bytes());
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return false,
+                Ok(written) => {
+                    self.pending.drain(..written);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Send an event to all connected clients, if the event socket is enabled.
+pub fn publish(event: &Event) {
+    let Some(events) = EVENTS.get() else {
+        return;
+    };
+    match serde_json::to_string(event) {
+        Ok(mut line) => {
+            line.push('\n');
+            // the writer thread only stops when the sender is dropped, which never happens
+            let _ = events.send(line);
+        }
+        Err(err) => error!("serializing event failed: {err}"),
+    }
+}
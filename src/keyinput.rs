@@ -1,8 +1,35 @@
-use std::{path::PathBuf, sync::mpsc::Sender};
+use std::{
+    io::stdin,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex, mpsc::Sender},
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
+use tracing::{error, info, warn};
 use xkbcommon::xkb;
 
 const KEYCODE_OFFSET: u16 = 8;
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Whether the main thread is done processing the last line read from stdin.
+static STDIN_PROCESSED: (Mutex<bool>, Condvar) = (Mutex::new(true), Condvar::new());
+
+/// A line read from one of the input devices.
+#[derive(Debug)]
+pub struct InputLine {
+    pub device: String,
+    pub line: String,
+    /// When the line was complete, which may be a while before it gets processed.
+    pub received_at: Instant,
+}
+
+#[derive(Debug)]
+pub enum InputEvent {
+    Line(InputLine),
+    /// No more input will arrive, e.g. because stdin was closed or a signal was received.
+    Shutdown,
+}
 
 enum KeyState {
     Release,
@@ -23,10 +50,71 @@ impl TryFrom<i32> for KeyState {
     }
 }
 
-pub fn read_input(device_path: &PathBuf, tx: Sender<String>) {
-    // Open evdev device
-    let mut device = evdev::Device::open(device_path).expect("Could not open device");
-    device.grab().expect("Could not exclusively grab device");
+/// Open the evdev device and grab it exclusively, retrying until this succeeds.
+fn open_device(device_path: &Path) -> evdev::Device {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        let res =
+            evdev::Device::open(device_path).and_then(|mut device| device.grab().map(|_| device));
+        match res {
+            Ok(device) => return device,
+            Err(err) => warn!(
+                "could not open {}: {err}, retrying in {}s",
+                device_path.display(),
+                delay.as_secs()
+            ),
+        }
+        sleep(delay);
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Signal that the last input line has been processed. This is required when reading from
+/// stdin, since the main thread also reads answers to its prompts from there.
+pub fn input_processed() {
+    let (processed, cvar) = &STDIN_PROCESSED;
+    *processed.lock().unwrap() = true;
+    cvar.notify_one();
+}
+
+fn read_stdin(tx: Sender<InputEvent>) {
+    let (processed, cvar) = &STDIN_PROCESSED;
+    loop {
+        drop(
+            cvar.wait_while(processed.lock().unwrap(), |processed| !*processed)
+                .unwrap(),
+        );
+        let mut line = String::new();
+        match stdin().read_line(&mut line) {
+            Ok(0) => {
+                tx.send(InputEvent::Shutdown).unwrap();
+                return;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                error!("reading from stdin failed: {err}");
+                return;
+            }
+        }
+        let line = line.trim();
+        if !line.is_empty() {
+            *processed.lock().unwrap() = false;
+            tx.send(InputEvent::Line(InputLine {
+                device: "stdin".to_string(),
+                line: line.to_string(),
+                received_at: Instant::now(),
+            }))
+            .unwrap();
+        }
+    }
+}
+
+pub fn read_input(device_path: &PathBuf, tx: Sender<InputEvent>) {
+    if device_path == Path::new("-") || device_path == Path::new("stdin") {
+        return read_stdin(tx);
+    }
+
+    let mut device = open_device(device_path);
 
     // Create context
     let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
@@ -47,7 +135,24 @@ pub fn read_input(device_path: &PathBuf, tx: Sender<String>) {
     let mut state = xkb::State::new(&keymap);
     let mut linebuf = String::with_capacity(50);
     loop {
-        for event in device.fetch_events().unwrap() {
+        // collecting the events ends the borrow of the device, so it can be replaced
+        let events = device
+            .fetch_events()
+            .map(|events| events.collect::<Vec<_>>());
+        let events = match events {
+            Ok(events) => events,
+            Err(err) => {
+                error!("reading from {} failed: {err}", device_path.display());
+                // release the grab of the old device before trying to get it back
+                drop(device);
+                device = open_device(device_path);
+                state = xkb::State::new(&keymap);
+                linebuf.clear();
+                info!("reconnected to {}", device_path.display());
+                continue;
+            }
+        };
+        for event in events {
             if let evdev::EventSummary::Key(_, ev_keycode, dir) = event.destructure() {
                 let keystate = KeyState::try_from(dir).expect("Invalid keystate");
                 let xkb_keycode = (ev_keycode.0 + KEYCODE_OFFSET).into();
@@ -63,7 +168,12 @@ pub fn read_input(device_path: &PathBuf, tx: Sender<String>) {
                         let key = state.key_get_utf8(xkb_keycode);
                         if ev_keycode == evdev::KeyCode::KEY_ENTER {
                             if !linebuf.is_empty() {
-                                tx.send(linebuf.clone()).unwrap();
+                                tx.send(InputEvent::Line(InputLine {
+                                    device: device_path.display().to_string(),
+                                    line: linebuf.clone(),
+                                    received_at: Instant::now(),
+                                }))
+                                .unwrap();
                                 linebuf.clear();
                             }
                         } else if !key.is_empty() {
@@ -7,21 +7,36 @@ use brother_ql::{
 use datamatrix::{DataMatrix, SymbolList, placement::PathSegment};
 use derive_typst_intoval::{IntoDict, IntoValue};
 use image::DynamicImage;
+use qrcode::QrCode;
 use std::{
+    borrow::Cow,
     fmt::Write,
-    sync::{Arc, OnceLock},
-    thread::sleep,
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    str::FromStr,
+    sync::{
+        Arc, OnceLock,
+        mpsc::{self, Receiver, Sender},
+    },
+    thread::{self, sleep},
     time::Duration,
 };
+use tracing::{error, info, warn};
 use typst::foundations::{Bytes, Datetime, IntoValue};
 use typst::layout::PagedDocument;
 use typst::syntax::{FileId, Source};
 use typst::text::{Font, FontBook};
 use typst::utils::LazyHash;
 use typst::{Library, LibraryExt};
-use typst::{diag::FileResult, foundations::Dict};
+use typst::{
+    diag::{FileError, FileResult},
+    foundations::Dict,
+};
 use typst_kit::fonts::{FontSearcher, FontSlot};
 
+use crate::code::{encode_item_code, encode_stock_code};
+use crate::config::config;
 use crate::models::{Item, Stock};
 
 #[allow(clippy::type_complexity)]
@@ -32,18 +47,105 @@ static FONT_DATA: OnceLock<(
     Arc<Vec<Font>>,
 )> = OnceLock::new();
 
+static PRINT_QUEUE: OnceLock<Sender<Vec<LabelContent>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeKind {
+    DataMatrix,
+    Qr,
+}
+
+impl FromStr for CodeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "datamatrix" => Ok(CodeKind::DataMatrix),
+            "qr" => Ok(CodeKind::Qr),
+            _ => Err("expected 'datamatrix' or 'qr'".to_string()),
+        }
+    }
+}
+
+/// The supported continuous-length label rolls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelMedia {
+    C12,
+    C29,
+    C38,
+    C50,
+    C54,
+    C62,
+}
+
+impl LabelMedia {
+    fn media(self) -> Media {
+        match self {
+            LabelMedia::C12 => Media::C12,
+            LabelMedia::C29 => Media::C29,
+            LabelMedia::C38 => Media::C38,
+            LabelMedia::C50 => Media::C50,
+            LabelMedia::C54 => Media::C54,
+            LabelMedia::C62 => Media::C62,
+        }
+    }
+
+    /// The printable width and the label length in pixels.
+    fn dimensions(self) -> (u16, u16) {
+        match self {
+            LabelMedia::C12 => (106, 150),
+            LabelMedia::C29 => (306, 150),
+            LabelMedia::C38 => (413, 150),
+            LabelMedia::C50 => (554, 150),
+            LabelMedia::C54 => (590, 150),
+            LabelMedia::C62 => (696, 150),
+        }
+    }
+}
+
+impl FromStr for LabelMedia {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "C12" => Ok(LabelMedia::C12),
+            "C29" => Ok(LabelMedia::C29),
+            "C38" => Ok(LabelMedia::C38),
+            "C50" => Ok(LabelMedia::C50),
+            "C54" => Ok(LabelMedia::C54),
+            "C62" => Ok(LabelMedia::C62),
+            _ => Err("expected one of C12, C29, C38, C50, C54, C62".to_string()),
+        }
+    }
+}
+
 pub struct LabelContent {
     pub name: String,
     pub date: String,
     pub code: String,
+    pub ean: Option<String>,
 }
 
 impl LabelContent {
     pub fn from_item_stock(item: &Item, stock: &Stock) -> Self {
         Self {
-            name: item.name.clone(),
-            date: stock.added_dt.date_naive().format("%m/%y").to_string(),
-            code: format!("~{}|{}~", stock.item_id, stock.id),
+            name: truncate(&item.name, config().label_max_name_len),
+            date: match stock.expiry_dt {
+                Some(expiry) => format!("exp. {}", expiry.format("%d.%m.%y")),
+                None => stock.added_dt.date_naive().format("%m/%y").to_string(),
+            },
+            code: encode_stock_code(stock.item_id, stock.id),
+            ean: item.ean.clone(),
+        }
+    }
+
+    /// A label that stands for any unit of the item.
+    pub fn from_item(item: &Item) -> Self {
+        Self {
+            name: truncate(&item.name, config().label_max_name_len),
+            date: String::new(),
+            code: encode_item_code(item.id),
+            ean: item.ean.clone(),
         }
     }
 
@@ -53,33 +155,128 @@ impl LabelContent {
             name: name.to_string(),
             date: date.to_string(),
             code: code.to_string(),
+            ean: None,
         }
     }
 }
 
-pub fn print_custom_item_labels(labels: &[LabelContent]) -> Result<()> {
-    let info = loop {
-        if let Some(info) = UsbConnectionInfo::discover()? {
-            break info;
+fn truncate(text: &str, max_len: usize) -> String {
+    if text.chars().count() <= max_len {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_len.saturating_sub(1)).collect();
+    truncated.truncate(truncated.trim_end().len());
+    truncated.push('…');
+    truncated
+}
+
+/// Queue labels for printing in the background, so scanning can continue meanwhile. If a batch
+/// window is configured, they are printed in one job together with any other labels queued
+/// within that window.
+///
+/// Printing failures are only logged, since they happen after this function has returned.
+pub fn print_labels(labels: Vec<LabelContent>) -> Result<()> {
+    let queue = PRINT_QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || print_worker(rx, config().label_batch_window));
+        tx
+    });
+    let count = labels.len();
+    queue
+        .send(labels)
+        .map_err(|_| anyhow::anyhow!("Label print queue is gone"))?;
+    info!("  queued {count} labels");
+    Ok(())
+}
+
+fn print_worker(rx: Receiver<Vec<LabelContent>>, window: Option<Duration>) {
+    while let Ok(mut batch) = rx.recv() {
+        // keep collecting until nothing new arrived for a whole window
+        if let Some(window) = window {
+            while let Ok(labels) = rx.recv_timeout(window) {
+                batch.extend(labels);
+            }
         }
-        println!("No printer found, maybe it's turned off?");
-        sleep(Duration::from_secs(1));
-    };
+        info!("printing {} queued labels", batch.len());
+        // a panic must not take down the worker, or nothing could be printed until a restart
+        let res = panic::catch_unwind(AssertUnwindSafe(|| print_custom_item_labels(&batch)))
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("printing panicked")));
+        if let Err(err) = res {
+            let codes: Vec<_> = batch.iter().map(|label| label.code.as_str()).collect();
+            error!("printing queued labels failed: {err}");
+            warn!(
+                "use the reprint op to print them later: {}",
+                codes.join(", ")
+            );
+        }
+    }
+}
+
+pub fn print_custom_item_labels(labels: &[LabelContent]) -> Result<()> {
     let images: Vec<_> = labels
         .iter()
-        .map(|content| {
-            println!(
+        .filter_map(|content| {
+            info!(
                 "  generating label: code={} name='{}' date={}",
                 content.code, content.name, content.date
             );
-            generate_label(&content.name, &content.code, &content.date)
+            // a single bad label should not keep the others from being printed
+            generate_label(
+                &content.name,
+                &content.code,
+                &content.date,
+                content.ean.as_deref(),
+            )
+            .inspect_err(|err| error!("  generating label {} failed: {err}", content.code))
+            .ok()
         })
         .collect();
+    if images.is_empty() {
+        anyhow::bail!("No label could be generated");
+    }
+    print_images(images)
+}
+
+/// Print a list of item names on a single label.
+pub fn print_shopping_list(names: Vec<String>) -> Result<()> {
+    const TEMPLATE: &str = include_str!("../typst/shopping_list.typ");
+    let (width, _) = config().label_media.dimensions();
+    let inputs = ShoppingListInput {
+        width,
+        items: names.iter().map(|name| sanitize(name)).collect(),
+    };
+    let image = render(TEMPLATE, inputs.into_dict())?;
+    print_images(vec![image])
+}
+
+/// Check whether a label printer is connected, without waiting for it to show up.
+pub fn printer_available() -> Result<bool> {
+    Ok(UsbConnectionInfo::discover()?.is_some())
+}
+
+fn print_images(images: Vec<DynamicImage>) -> Result<()> {
+    const PRINTER_ATTEMPTS: u32 = 10;
+    if config().dry_run {
+        info!("  dry run, not printing {} labels", images.len());
+        return Ok(());
+    }
+    let mut attempt = 0;
+    let info = loop {
+        if let Some(info) = UsbConnectionInfo::discover()? {
+            break info;
+        }
+        attempt += 1;
+        if attempt == PRINTER_ATTEMPTS {
+            anyhow::bail!("No printer found");
+        }
+        warn!("No printer found, maybe it's turned off?");
+        sleep(Duration::from_secs(1));
+    };
     let mut conn = UsbConnection::open(info)?;
-    println!("  printing {} labels", images.len());
+    info!("  printing {} labels", images.len());
     let mut it = images.into_iter();
-    let job = PrintJobBuilder::new(Media::C62)
-        .add_label(it.next().expect("Added at least one stock item"))
+    let job = PrintJobBuilder::new(config().label_media.media())
+        .add_label(it.next().expect("Added at least one label"))
         .add_labels(it)
         .build()?;
     conn.print(job)?;
@@ -87,6 +284,13 @@ pub fn print_custom_item_labels(labels: &[LabelContent]) -> Result<()> {
 }
 
 fn generate_code_svg(code: &str) -> String {
+    match config().label_code {
+        CodeKind::DataMatrix => generate_datamatrix_svg(code),
+        CodeKind::Qr => generate_qr_svg(code),
+    }
+}
+
+fn generate_datamatrix_svg(code: &str) -> String {
     let bitmap = DataMatrix::encode(code.as_bytes(), SymbolList::default().enforce_square())
         .expect("Generating barcode should never fail")
         .bitmap();
@@ -114,36 +318,95 @@ fn generate_code_svg(code: &str) -> String {
     svg
 }
 
-pub fn generate_label(name: &str, code: &str, date: &str) -> DynamicImage {
+fn generate_qr_svg(code: &str) -> String {
+    let qr = QrCode::new(code.as_bytes()).expect("Generating QR code should never fail");
+    let width = qr.width();
+
+    let mut svg: String = format!(
+        concat!(
+            r#"<?xml version="1.0"?>"#,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}">"#,
+            r#"<path d="M0,0"#,
+        ),
+        width, width
+    );
+    for (i, color) in qr.to_colors().into_iter().enumerate() {
+        if color == qrcode::Color::Dark {
+            write!(svg, "M{},{}h1v1h-1z", i % width, i / width)
+                .expect("Writing to string should never fail");
+        }
+    }
+    svg.push_str(r#""/></svg>"#);
+    svg
+}
+
+pub fn generate_label(
+    name: &str,
+    code: &str,
+    date: &str,
+    ean: Option<&str>,
+) -> Result<DynamicImage> {
     let svg = generate_code_svg(code);
+    let (width, height) = config().label_media.dimensions();
 
     let inputs = LabelInput {
-        width: 696,
-        height: 150,
-        name: name.to_string(),
-        date: date.to_string(),
+        width,
+        height,
+        name: sanitize(name),
+        date: sanitize(date),
         code: Bytes::from_string(svg),
+        ean: ean.map(sanitize),
     };
-    let world = TypstWrapperWorld::new(include_str!("../typst/label.typ"), inputs.into_dict());
+    render(&label_template(), inputs.into_dict())
+}
+
+/// Clean up text for a label. It is passed to typst as a string input, so markup characters
+/// like `#` or `[` are not interpreted, but control characters (e.g. newlines in names from
+/// openfoodfacts) would still end up on the label.
+fn sanitize(text: &str) -> String {
+    text.split(|c: char| c.is_whitespace() || c.is_control())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Compile a typst template with the given inputs and render its first page.
+fn render(source: &str, inputs: Dict) -> Result<DynamicImage> {
+    let world = TypstWrapperWorld::new(source, inputs);
 
     let document: PagedDocument = typst::compile(&world)
         .output
-        .map_err(|err| anyhow::anyhow!(format!("Typst compilation failed: {err:?}")))
-        .unwrap();
+        .map_err(|err| anyhow::anyhow!(format!("Typst compilation failed: {err:?}")))?;
 
     let pages: Vec<_> = document.pages.iter().collect();
     let page = pages
         .first()
-        .ok_or_else(|| anyhow::anyhow!("Compiled document has no pages".to_string()))
-        .unwrap();
+        .ok_or_else(|| anyhow::anyhow!("Compiled document has no pages".to_string()))?;
 
     let pixmap = typst_render::render(page, 1.0);
     let buf = pixmap
         .encode_png()
-        .map_err(|err| anyhow::anyhow!(format!("PNG encoding failed: {err}")))
-        .unwrap();
+        .map_err(|err| anyhow::anyhow!(format!("PNG encoding failed: {err}")))?;
 
-    image::load_from_memory(&buf).unwrap()
+    Ok(image::load_from_memory(&buf)?)
+}
+
+fn label_template() -> Cow<'static, str> {
+    const DEFAULT_TEMPLATE: &str = include_str!("../typst/label.typ");
+    let Some(path) = &config().label_template else {
+        return Cow::Borrowed(DEFAULT_TEMPLATE);
+    };
+    // read it every time so changes to the template do not require a restart
+    match fs::read_to_string(path) {
+        Ok(source) => Cow::Owned(source),
+        Err(err) => {
+            warn!(
+                "Could not read label template {}, using the default: {err}",
+                path.display()
+            );
+            Cow::Borrowed(DEFAULT_TEMPLATE)
+        }
+    }
 }
 
 #[derive(Debug, Clone, IntoValue, IntoDict)]
@@ -153,6 +416,13 @@ struct LabelInput {
     name: String,
     date: String,
     code: Bytes,
+    ean: Option<String>,
+}
+
+#[derive(Debug, Clone, IntoValue, IntoDict)]
+struct ShoppingListInput {
+    width: u16,
+    items: Vec<String>,
 }
 
 // The typst integration is based on the example from the brother_ql library:
@@ -176,22 +446,21 @@ impl TypstWrapperWorld {
     fn new(source: &str, inputs: Dict) -> Self {
         let (book, fonts, custom_font_offset, custom_fonts) = FONT_DATA.get_or_init(|| {
             let mut fonts = FontSearcher::new().include_system_fonts(false).search();
-            // Add custom embedded font. This is super awful because lots of important parts are
-            // private and thus need to be worked around (e.g. getting the number of fonts already
-            // in the font book)
-            let mut offset = 0;
-            loop {
-                if fonts.book.info(offset).is_none() {
-                    break;
-                }
-                offset += 1;
-            }
-            let mut custom_fonts = Vec::new();
-            for font in Font::iter(Bytes::new(include_bytes!(
+            // Add custom fonts. This is super awful because lots of important parts are private
+            // and thus need to be worked around (e.g. getting the number of fonts already in the
+            // font book)
+            let offset = (0..)
+                .take_while(|&idx| fonts.book.info(idx).is_some())
+                .count();
+            let mut custom_fonts: Vec<_> = Font::iter(Bytes::new(include_bytes!(
                 "../typst/LiberationSans-Regular.ttf"
-            ))) {
+            )))
+            .collect();
+            if let Some(dir) = &config().label_font_dir {
+                custom_fonts.extend(load_font_dir(dir));
+            }
+            for font in &custom_fonts {
                 fonts.book.push(font.info().clone());
-                custom_fonts.push(font);
             }
             (
                 LazyHash::new(fonts.book),
@@ -211,6 +480,44 @@ impl TypstWrapperWorld {
     }
 }
 
+fn load_font_dir(dir: &Path) -> Vec<Font> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Could not read font directory {}: {err}", dir.display());
+            return Vec::new();
+        }
+    };
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| {
+                    matches!(ext.to_lowercase().as_str(), "ttf" | "otf" | "ttc" | "otc")
+                })
+        })
+        .collect();
+    // keep the font order stable between runs
+    paths.sort();
+    let mut fonts = Vec::new();
+    for path in paths {
+        match fs::read(&path) {
+            Ok(data) => {
+                let before = fonts.len();
+                fonts.extend(Font::iter(Bytes::new(data)));
+                info!(
+                    "loaded {} fonts from {}",
+                    fonts.len() - before,
+                    path.display()
+                );
+            }
+            Err(err) => warn!("Could not read font {}: {err}", path.display()),
+        }
+    }
+    fonts
+}
+
 impl typst::World for TypstWrapperWorld {
     /// Standard library.
     fn library(&self) -> &LazyHash<Library> {
@@ -227,18 +534,19 @@ impl typst::World for TypstWrapperWorld {
         self.source.id()
     }
 
-    /// Accessing a specified source file (based on `FileId`).
+    /// Accessing a specified source file (based on `FileId`). Templates are self-contained, so
+    /// imports of other files fail.
     fn source(&self, id: FileId) -> FileResult<Source> {
         if id == self.source.id() {
             Ok(self.source.clone())
         } else {
-            panic!("Not implemented (nor needed)!")
+            Err(FileError::NotFound(id.vpath().as_rootless_path().into()))
         }
     }
 
-    /// Accessing a specified file (non-file).
-    fn file(&self, _id: FileId) -> FileResult<Bytes> {
-        panic!("Not implemented (nor needed)!")
+    /// Accessing a specified file (non-file), e.g. an image. None are available.
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        Err(FileError::NotFound(id.vpath().as_rootless_path().into()))
     }
 
     /// Accessing a specified font per index of font book.
@@ -1,3 +1,5 @@
+pub mod code;
+pub mod config;
 pub mod labels;
 mod models;
 mod schema;
@@ -1,31 +1,53 @@
+use crate::cli::{Cli, Command, ExportFormat};
+use crate::code::{decode_item_code, decode_stock_code, encode_stock_code, parse_gs1};
+use crate::config::{Config, config, init_config};
 use crate::db::{
-    add_to_stock, connect_db, create_alias, create_item, finish_from_stock, open_from_stock,
-    query_item_by_ean, query_item_by_id, query_item_by_name, query_item_stock, remove_from_stock,
-    search_custom_items_by_name,
+    add_to_stock, backup_eans, check_consistency, connect_db, create_item, delete_alias,
+    delete_item, ensure_item, export_backup, finish_from_stock, finish_stock_by_id, import_backup,
+    init_pool, list_aliases_for, merge_items, open_from_stock, query_consumption,
+    query_deleted_item_by_ean, query_expiring_soon, query_inventory, query_item_by_ean,
+    query_item_by_id, query_item_by_name, query_items_by_ean_prefix, query_low_stock,
+    query_overdue_open, query_shopping_list, query_stock_by_id, query_stock_entries,
+    query_stock_summary, remove_from_stock, rename_item, run_migrations,
+    search_custom_items_by_name, set_min_stock, set_note, set_open_shelf_days,
+    set_remaining_fraction, set_stock_count, undelete_item, undo_add, undo_open, undo_remove,
+    update_stock_location,
 };
-use crate::keyinput::read_input;
-use crate::labels::{LabelContent, print_custom_item_labels};
-use crate::models::{Item, Stock};
+use crate::events::Event;
+use crate::keyinput::{InputEvent, InputLine, input_processed, read_input};
+use crate::labels::{
+    LabelContent, generate_label, print_custom_item_labels, print_labels, print_shopping_list,
+    printer_available,
+};
+use crate::models::{Backup, Item, ItemDetails, ItemKind, Stock};
+use crate::off::lookup;
 use anyhow::Result;
-use diesel::Connection;
+use chrono::{DateTime, Local, Months, NaiveDate};
 use dotenvy::dotenv;
-use openfoodfacts::{self as off, Output};
-use serde_json::{Value, json};
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::{str::FromStr, sync::mpsc, thread};
 use termios::{TCIOFLUSH, tcflush};
-use text_io::{read, try_scan};
+use text_io::read;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
+mod cli;
+mod code;
+mod config;
 mod db;
+mod events;
 mod keyinput;
 mod labels;
+mod metrics;
 mod models;
+mod off;
 mod schema;
-// mod web;
+mod web;
 
-static IDLE_TIMEOUT: u64 = 120;
+static EXPIRY_WARNING_DAYS: i64 = 7;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 enum ScanOp {
@@ -35,25 +57,205 @@ enum ScanOp {
     Remove,
     Open,
     Finish,
+    MinStock,
+    OpenShelfDays,
+    Remaining,
+    Rename,
+    Note,
+    Reprint,
+    Delete,
+    Move,
+    SetCount,
+    Count,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum EanKind {
+    Ean8,
+    UpcA,
+    Ean13,
+    /// A GTIN-14 with a packaging indicator, as found in GS1 codes on outer packaging.
+    Gtin14,
+}
+
+/// A stock change made during this session, which can be reverted by scanning the undo code.
+#[derive(Debug)]
+struct StockChange {
+    op: ScanOp,
+    item: Item,
+    /// The changed stock entries, which are more than one when opening a whole batch.
+    stock_ids: Vec<i32>,
+    /// How many units were changed, e.g. the count when adding.
+    quantity: u32,
+}
+
+/// The result of a stock operation, which is reported to the user by the caller.
+#[derive(Debug)]
+enum OperationOutcome {
+    /// The stock was changed, and this many units are still available (if known).
+    Done {
+        change: StockChange,
+        remaining: Option<i64>,
+    },
+    /// Nothing was changed, e.g. because the item is not in stock.
+    Rejected(anyhow::Error),
+}
+
+/// What happened since the scanner last went idle.
+#[derive(Debug, Default)]
+struct SessionSummary {
+    added: u32,
+    removed: u32,
+    opened: u32,
+    finished: u32,
+    registered: u32,
+}
+
+impl SessionSummary {
+    fn record(&mut self, change: &StockChange) {
+        metrics::record_operation(&format!("{:?}", change.op).to_lowercase());
+        match change.op {
+            ScanOp::Add => self.added += change.quantity,
+            ScanOp::Remove => self.removed += change.quantity,
+            ScanOp::Open => self.opened += change.quantity,
+            ScanOp::Finish => self.finished += change.quantity,
+            _ => {}
+        }
+    }
+
+    fn revert(&mut self, change: &StockChange) {
+        let counter = match change.op {
+            ScanOp::Add => &mut self.added,
+            ScanOp::Remove => &mut self.removed,
+            ScanOp::Open => &mut self.opened,
+            ScanOp::Finish => &mut self.finished,
+            _ => return,
+        };
+        *counter = counter.saturating_sub(change.quantity);
+    }
+
+    fn record_registration(&mut self) {
+        metrics::record_operation("register");
+        self.registered += 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added == 0
+            && self.removed == 0
+            && self.opened == 0
+            && self.finished == 0
+            && self.registered == 0
+    }
+}
+
+impl ScanOp {
+    /// The scan code that switches to this op.
+    fn code(self) -> &'static str {
+        let codes = &config().scan_codes;
+        match self {
+            ScanOp::None => &codes.none,
+            ScanOp::Register => &codes.register,
+            ScanOp::Add => &codes.add,
+            ScanOp::Remove => &codes.remove,
+            ScanOp::Open => &codes.open,
+            ScanOp::Finish => &codes.finish,
+            ScanOp::MinStock => &codes.min_stock,
+            ScanOp::OpenShelfDays => &codes.open_shelf_days,
+            ScanOp::Remaining => &codes.remaining,
+            ScanOp::Rename => &codes.rename,
+            ScanOp::Note => &codes.note,
+            ScanOp::Reprint => &codes.reprint,
+            ScanOp::Delete => &codes.delete,
+            ScanOp::Move => &codes.move_location,
+            ScanOp::SetCount => &codes.set_count,
+            ScanOp::Count => &codes.count,
+        }
+    }
+
+    /// Whether scans under this op are expected to change the stock.
+    fn changes_stock(self) -> bool {
+        matches!(
+            self,
+            ScanOp::Add | ScanOp::Remove | ScanOp::Open | ScanOp::Finish
+        )
+    }
+
+    /// Split a line like `>>>4006381333931` into the op and the barcode, for scanners that
+    /// send both in one go.
+    fn split_prefix(line: &str) -> Option<(ScanOp, &str)> {
+        line.char_indices().skip(1).find_map(|(idx, _)| {
+            ScanOp::from_str(&line[..idx])
+                .ok()
+                .map(|op| (op, &line[idx..]))
+        })
+    }
 }
 
 impl FromStr for ScanOp {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let codes = &config().scan_codes;
         match s {
-            "???" => Ok(ScanOp::None),
-            "+++" => Ok(ScanOp::Register),
-            ">>>" => Ok(ScanOp::Add),
-            "<<<" => Ok(ScanOp::Remove),
-            "///" => Ok(ScanOp::Open),
-            "</<" => Ok(ScanOp::Finish),
-            // ~+~ => create custom: handled separately, it's an action and not an op that affects later scans
+            s if s == codes.none => Ok(ScanOp::None),
+            s if s == codes.register => Ok(ScanOp::Register),
+            s if s == codes.add => Ok(ScanOp::Add),
+            s if s == codes.remove => Ok(ScanOp::Remove),
+            s if s == codes.open => Ok(ScanOp::Open),
+            s if s == codes.finish => Ok(ScanOp::Finish),
+            s if s == codes.min_stock => Ok(ScanOp::MinStock),
+            s if s == codes.open_shelf_days => Ok(ScanOp::OpenShelfDays),
+            s if s == codes.remaining => Ok(ScanOp::Remaining),
+            s if s == codes.rename => Ok(ScanOp::Rename),
+            s if s == codes.note => Ok(ScanOp::Note),
+            s if s == codes.reprint => Ok(ScanOp::Reprint),
+            s if s == codes.delete => Ok(ScanOp::Delete),
+            s if s == codes.move_location => Ok(ScanOp::Move),
+            s if s == codes.set_count => Ok(ScanOp::SetCount),
+            s if s == codes.count => Ok(ScanOp::Count),
+            // create custom: handled separately, it's an action and not an op that affects later scans
+            // list expiring, shopping list, merge, remove alias, undo: same as above
             _ => Err(()),
         }
     }
 }
 
+/// Remember the scan op in the state file, if one is configured. This is done after every scan,
+/// so the file's modification time tells when the scanner was last used.
+fn save_op(op: ScanOp) {
+    let Some(path) = &config().state_file else {
+        return;
+    };
+    if let Err(err) = std::fs::write(path, op.code()) {
+        warn!("could not save scan op to {}: {err}", path.display());
+    }
+}
+
+/// Restore the scan op from the state file, unless it would have been reset due to the idle
+/// timeout in the meantime.
+fn restore_op() -> ScanOp {
+    let Some(path) = &config().state_file else {
+        return ScanOp::None;
+    };
+    let Ok(modified) = std::fs::metadata(path).and_then(|meta| meta.modified()) else {
+        return ScanOp::None;
+    };
+    if modified
+        .elapsed()
+        .is_ok_and(|idle| idle >= config().idle_timeout)
+    {
+        return ScanOp::None;
+    }
+    let op = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|code| ScanOp::from_str(code.trim()).ok())
+        .unwrap_or(ScanOp::None);
+    if op != ScanOp::None {
+        info!("scan op restored: {op:?}");
+    }
+    op
+}
+
 fn find_device() -> Result<PathBuf> {
     let mut enumerator = udev::Enumerator::new()?;
     enumerator.match_is_initialized()?;
@@ -66,69 +268,440 @@ fn find_device() -> Result<PathBuf> {
     devpath.ok_or(anyhow::anyhow!("no device found"))
 }
 
+fn init_logging() {
+    // the app-specific variable takes precedence over the generic one
+    let filter = EnvFilter::try_from_env("LARDER_LOG")
+        .or_else(|_| EnvFilter::try_from_default_env())
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .init();
+}
+
 fn main() -> Result<()> {
     dotenv().ok();
-    let device_path = match std::env::args().nth(1).map(PathBuf::from) {
-        Some(path) => path,
-        None => find_device()?,
+    init_logging();
+    let mut cfg = Config::from_env()?;
+    let cli = Cli::parse_with_codes(&cfg.scan_codes);
+    let command = cli.command.unwrap_or(Command::Run(cli.run));
+    cfg.dry_run |= cli.dry_run;
+    if let Command::Run(args) = &command
+        && let Some(secs) = args.idle_timeout
+    {
+        cfg.idle_timeout = Duration::from_secs(secs);
+    }
+    init_config(cfg);
+    match &command {
+        Command::PreviewLabel {
+            name,
+            date,
+            output,
+            ean,
+        } => return preview_label(name, date.as_deref(), output, ean.as_deref()),
+        Command::Healthcheck => return healthcheck(),
+        _ => {}
+    }
+    init_pool()?;
+    if config().auto_migrate {
+        run_migrations()?;
+    }
+    let run_args = match command {
+        Command::Run(args) => args,
+        Command::Export {
+            path,
+            format: ExportFormat::Csv,
+            active_only,
+        } => return export_csv(&path, active_only),
+        Command::Export {
+            path,
+            format: ExportFormat::Json,
+            ..
+        } => return export_json(&path),
+        Command::Import { path, merge } => return import_json(&path, merge),
+        Command::ShoppingList => return show_shopping_list(),
+        Command::Stats { weeks } => return show_stats(weeks),
+        Command::PrintItemLabels { name, count } => return print_item_labels(&name, count),
+        Command::PreviewLabel { .. } | Command::Healthcheck => {
+            unreachable!("handled before connecting to the database")
+        }
     };
+    if let Err(err) = report_low_stock() {
+        error!("checking for low stock failed: {err}");
+    }
+    if let Err(err) = report_inconsistencies() {
+        error!("checking data consistency failed: {err}");
+    }
+    // several scanners can be used at the same time
+    let mut device_paths: Vec<PathBuf> = run_args
+        .devices
+        .into_iter()
+        .chain(run_args.device_args)
+        .collect();
+    if device_paths.is_empty() {
+        device_paths.push(find_device()?);
+    }
 
     let (tx, rx) = mpsc::channel();
-    thread::spawn(move || read_input(&device_path, tx));
+    for device_path in device_paths {
+        let tx = tx.clone();
+        thread::spawn(move || read_input(&device_path, tx));
+    }
+    install_signal_handler(tx)?;
 
-    let mut op = ScanOp::None;
-    let idle_timeout = Duration::from_secs(IDLE_TIMEOUT);
+    if let Some(port) = config().web_port {
+        thread::spawn(move || {
+            if let Err(err) = web::serve(port) {
+                error!("web server failed: {err}");
+            }
+        });
+    }
+    if let Some(path) = config().event_socket.clone() {
+        thread::spawn(move || {
+            if let Err(err) = events::serve(&path) {
+                error!("event socket failed: {err}");
+            }
+        });
+    }
+
+    let codes = &config().scan_codes;
+    let mut op = restore_op();
+    let mut history = Vec::<StockChange>::new();
+    let mut summary = SessionSummary::default();
+    let mut last_scan: Option<(String, String, Instant)> = None;
     loop {
-        match rx.recv_timeout(idle_timeout) {
-            Ok(line) => {
-                println!("recv: '{line}'");
-                if let Ok(new_op) = ScanOp::from_str(&line) {
+        match rx.recv_timeout(config().idle_timeout) {
+            Ok(InputEvent::Line(InputLine {
+                device,
+                line,
+                received_at,
+            })) => {
+                info!("recv from {device}: '{line}'");
+                let repeated = last_scan
+                    .as_ref()
+                    .is_some_and(|(prev_device, prev_line, at)| {
+                        *prev_device == device
+                            && *prev_line == line
+                            && received_at.duration_since(*at) < config().debounce
+                    });
+                last_scan = Some((device, line.clone(), received_at));
+                if repeated && is_debounced(op, &line) {
+                    warn!("ignoring repeated scan {line}");
+                    input_processed();
+                    continue;
+                }
+                let success = if let Ok(new_op) = ScanOp::from_str(&line) {
                     if new_op != op {
-                        println!("scan op changed: {op:?} -> {new_op:?}");
+                        info!("scan op changed: {op:?} -> {new_op:?}");
                         op = new_op;
                     }
-                } else if line == "~+~" {
-                    if let Err(err) = create_custom() {
-                        println!("creating custom item failed: {err}");
+                    true
+                } else if line == codes.create_custom {
+                    match create_custom() {
+                        Ok(change) => {
+                            summary.record(&change);
+                            history.push(change);
+                            true
+                        }
+                        Err(err) => {
+                            error!("creating custom item failed: {err}");
+                            false
+                        }
                     }
-                } else if let Some((item_id, stock_id)) = parse_custom_code(&line) {
-                    if let Err(err) = remove_custom(item_id, stock_id) {
-                        println!("removing custom item from stock failed: {err}");
+                } else if line == codes.merge {
+                    merge()
+                        .inspect_err(|err| error!("merging items failed: {err}"))
+                        .is_ok()
+                } else if line == codes.remove_alias {
+                    remove_alias()
+                        .inspect_err(|err| error!("removing alias failed: {err}"))
+                        .is_ok()
+                } else if line == codes.list_expiring {
+                    report_expiring()
+                        .inspect_err(|err| error!("listing expiring stock failed: {err}"))
+                        .is_ok()
+                } else if line == codes.shopping_list {
+                    print_shopping_list_label()
+                        .inspect_err(|err| error!("printing shopping list failed: {err}"))
+                        .is_ok()
+                } else if line == codes.undo {
+                    match undo(&mut history) {
+                        Ok(Some(change)) => {
+                            summary.revert(&change);
+                            true
+                        }
+                        Ok(None) => false,
+                        Err(err) => {
+                            error!("undoing last change failed: {err}");
+                            false
+                        }
                     }
-                } else if let Err(err) = scanned(op, &line) {
-                    println!("processing scan {line} failed: {err}");
-                }
+                } else if let Some((item_id, stock_id)) = decode_stock_code(&line)
+                    && op == ScanOp::Reprint
+                {
+                    reprint(item_id, stock_id)
+                        .inspect_err(|err| error!("reprinting label failed: {err}"))
+                        .is_ok()
+                } else if let Some((item_id, stock_id)) = decode_stock_code(&line)
+                    && op == ScanOp::Move
+                {
+                    move_custom(item_id, stock_id)
+                        .inspect_err(|err| error!("moving custom item failed: {err}"))
+                        .is_ok()
+                } else if let Some((item_id, stock_id)) = decode_stock_code(&line)
+                    && op == ScanOp::Finish
+                {
+                    let res = finish_custom(item_id, stock_id)
+                        .inspect_err(|err| error!("finishing custom item failed: {err}"));
+                    record_change(res, &mut summary, &mut history)
+                } else if let Some((item_id, stock_id)) = decode_stock_code(&line) {
+                    let res = remove_custom(item_id, stock_id)
+                        .inspect_err(|err| error!("removing custom item from stock failed: {err}"));
+                    record_change(res, &mut summary, &mut history)
+                } else if let Some(item_id) = decode_item_code(&line) {
+                    let res = custom_item_scanned(op, item_id)
+                        .inspect_err(|err| error!("processing custom item failed: {err}"));
+                    record_change(res, &mut summary, &mut history)
+                } else if let Some(prefix) = parse_ean_prefix(&line) {
+                    let res = pick_by_ean_prefix(prefix)
+                        .and_then(|ean| match ean {
+                            Some(ean) => scanned(op, &ean, None, &mut summary),
+                            None => Ok(None),
+                        })
+                        .inspect_err(|err| error!("searching partial barcode failed: {err}"));
+                    match res {
+                        Ok(None) if !op.changes_stock() => true,
+                        res => record_change(res, &mut summary, &mut history),
+                    }
+                } else {
+                    // a prefixed op only applies to this scan, the sticky op stays as it is
+                    let (op, barcode) = ScanOp::split_prefix(&line).unwrap_or((op, &line));
+                    // GS1 codes may contain the expiry date, which then does not need to be entered
+                    let gs1 = parse_gs1(barcode);
+                    let (barcode, expiry) = match &gs1 {
+                        Some(gs1) => (gs1.ean.as_str(), gs1.expiry),
+                        None => (barcode, None),
+                    };
+                    let res = scanned(op, barcode, expiry, &mut summary)
+                        .inspect_err(|err| error!("processing scan {line} failed: {err}"));
+                    match res {
+                        // only stock operations are expected to change something
+                        Ok(None) if !op.changes_stock() => true,
+                        res => record_change(res, &mut summary, &mut history),
+                    }
+                };
+                save_op(op);
+                feedback(success);
+                input_processed();
             }
             Err(mpsc::RecvTimeoutError::Timeout) => {
+                if !summary.is_empty() {
+                    report_summary(&summary);
+                    summary = SessionSummary::default();
+                }
                 if op != ScanOp::None {
-                    println!("scan op reset: {op:?} -> None");
+                    info!("scan op reset: {op:?} -> None");
                     op = ScanOp::None;
+                    save_op(op);
                 }
             }
+            Ok(InputEvent::Shutdown) => break,
             Err(mpsc::RecvTimeoutError::Disconnected) => {
-                panic!("Input channel disconnected");
+                anyhow::bail!("Input channel disconnected");
+            }
+        }
+    }
+    if !summary.is_empty() {
+        report_summary(&summary);
+    }
+    // exiting closes the input devices, which also releases their grabs
+    info!("shutting down");
+    Ok(())
+}
+
+/// Stop the main loop on SIGINT/SIGTERM. A second signal exits immediately, since the main
+/// thread cannot see the first one while it is waiting for an answer to a prompt.
+fn install_signal_handler(tx: mpsc::Sender<InputEvent>) -> Result<()> {
+    static RECEIVED: AtomicBool = AtomicBool::new(false);
+    ctrlc::set_handler(move || {
+        if RECEIVED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        info!("received shutdown signal");
+        // the main loop may already be gone, in which case there is nothing left to stop
+        let _ = tx.send(InputEvent::Shutdown);
+    })
+    .map_err(|err| anyhow::anyhow!("Could not install signal handler: {err}"))
+}
+
+/// Render a label to a PNG file instead of printing it, e.g. to check changes to the template.
+fn preview_label(name: &str, date: Option<&str>, path: &Path, ean: Option<&str>) -> Result<()> {
+    let date = date.map_or_else(
+        || chrono::Local::now().format("%m/%y").to_string(),
+        String::from,
+    );
+    // a code with realistic ids so the code has the same size as on real labels
+    let label = generate_label(name, &encode_stock_code(123, 4567), &date, ean)?;
+    label
+        .save_with_format(path, image::ImageFormat::Png)
+        .map_err(|err| anyhow::anyhow!("Could not save preview to {}: {err}", path.display()))?;
+    info!("Label preview saved to {}", path.display());
+    Ok(())
+}
+
+/// Write all stock entries, including removed ones unless `active_only` is set, to a CSV file.
+fn export_csv(path: &Path, active_only: bool) -> Result<()> {
+    let inventory = query_inventory(active_only)?;
+    let mut writer = csv::Writer::from_path(path)
+        .map_err(|err| anyhow::anyhow!("Could not create {}: {err}", path.display()))?;
+    writer.write_record([
+        "name", "kind", "ean", "quantity", "added", "opened", "removed", "expiry",
+    ])?;
+    let format_dt = |dt: Option<DateTime<Local>>| dt.map(|dt| dt.to_rfc3339()).unwrap_or_default();
+    for (item, stock) in &inventory {
+        writer.write_record([
+            item.name.as_str(),
+            match item.kind {
+                ItemKind::Bought => "bought",
+                ItemKind::Custom => "custom",
+            },
+            item.ean.as_deref().unwrap_or_default(),
+            stock.quantity.to_string().as_str(),
+            stock.added_dt.to_rfc3339().as_str(),
+            format_dt(stock.opened_dt).as_str(),
+            format_dt(stock.removed_dt).as_str(),
+            stock
+                .expiry_dt
+                .map(|date| date.format("%Y-%m-%d").to_string())
+                .unwrap_or_default()
+                .as_str(),
+        ])?;
+    }
+    writer.flush()?;
+    info!(
+        "Exported {} stock entries to {}",
+        inventory.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Check that the database and the printer are reachable, e.g. for monitoring.
+fn healthcheck() -> Result<()> {
+    let mut healthy = true;
+    match init_pool().and_then(|_| connect_db()) {
+        Ok(_) => info!("database: ok"),
+        Err(err) => {
+            error!("database: {err}");
+            healthy = false;
+        }
+    }
+    if config().dry_run {
+        info!("printer: not checked in dry run mode");
+    } else {
+        match printer_available() {
+            Ok(true) => info!("printer: ok"),
+            Ok(false) => {
+                error!("printer: not found");
+                healthy = false;
+            }
+            Err(err) => {
+                error!("printer: {err}");
+                healthy = false;
             }
         }
     }
+    if !healthy {
+        anyhow::bail!("Health check failed");
+    }
+    Ok(())
+}
+
+fn export_json(path: &Path) -> Result<()> {
+    let backup = export_backup()?;
+    let file = std::fs::File::create(path)
+        .map_err(|err| anyhow::anyhow!("Could not create {}: {err}", path.display()))?;
+    serde_json::to_writer_pretty(file, &backup)?;
+    info!(
+        "Exported {} items, {} aliases, {} stock entries, {} stock log entries and {} shopping list entries to {}",
+        backup.items.len(),
+        backup.eans.len(),
+        backup.stock.len(),
+        backup.stock_log.len(),
+        backup.shopping_list.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Import a JSON export, either into an empty database or, with `merge`, into an existing one.
+fn import_json(path: &Path, merge: bool) -> Result<()> {
+    let file = std::fs::File::open(path)
+        .map_err(|err| anyhow::anyhow!("Could not open {}: {err}", path.display()))?;
+    let backup: Backup = serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|err| anyhow::anyhow!("Could not parse {}: {err}", path.display()))?;
+    import_backup(&backup, merge, None)?;
+    info!(
+        "Imported {} items, {} aliases, {} stock entries, {} stock log entries and {} shopping list entries from {}",
+        backup.items.len(),
+        backup_eans(&backup).len(),
+        backup.stock.len(),
+        backup.stock_log.len(),
+        backup.shopping_list.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Whether scanning a line twice in quick succession would do something twice. Changing the op
+/// or looking at an item again is harmless, but adding it twice is not.
+fn is_debounced(op: ScanOp, line: &str) -> bool {
+    if ScanOp::from_str(line).is_ok() {
+        return false;
+    }
+    let (op, barcode) = ScanOp::split_prefix(line).unwrap_or((op, line));
+    if validate_ean(barcode).is_some() {
+        op.changes_stock()
+    } else {
+        // custom codes and actions like undo
+        true
+    }
 }
 
-fn parse_custom_code(line: &str) -> Option<(i32, i32)> {
-    // AFAICT, `try_read!` does not support more than one placeholder, and
-    // unfortunately `try_scan!` includes a hardcoded `?` for error handling,
-    // so we need this extra function to get the Result which we can then
-    // convert to na Option
-    let inner = || -> Result<(i32, i32)> {
-        let (item_id, stock_id): (i32, i32);
-        try_scan!(line.bytes() => "~{}|{}~", item_id, stock_id);
-        Ok((item_id, stock_id))
+/// Check whether a scanned code is a well-formed EAN, UPC or GTIN-14, including its check digit.
+fn validate_ean(code: &str) -> Option<EanKind> {
+    if !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let kind = match code.len() {
+        8 => EanKind::Ean8,
+        12 => EanKind::UpcA,
+        13 => EanKind::Ean13,
+        14 => EanKind::Gtin14,
+        _ => return None,
     };
-    inner().ok()
+    // starting with the check digit, the digits are weighted 1, 3, 1, 3, ... from the right
+    let sum: u32 = code
+        .bytes()
+        .rev()
+        .enumerate()
+        .map(|(i, b)| u32::from(b - b'0') * if i % 2 == 0 { 1 } else { 3 })
+        .sum();
+    (sum % 10 == 0).then_some(kind)
+}
+
+/// Discard pending input such as codes scanned before a prompt was shown. Only a terminal can
+/// be flushed, piped input is left alone.
+fn flush_input() {
+    if std::io::stdin().is_terminal() {
+        let _ = tcflush(0, TCIOFLUSH);
+    }
 }
 
-fn create_custom() -> Result<()> {
-    println!("Adding custom item");
+fn create_custom() -> Result<StockChange> {
+    info!("Adding custom item");
     print!("  enter name: ");
-    tcflush(0, TCIOFLUSH).unwrap();
+    flush_input();
     let name: String = read!("{}\n");
     if name.is_empty() {
         println!();
@@ -138,19 +711,26 @@ fn create_custom() -> Result<()> {
     let item = if let [cand] = candidates.as_slice()
         && cand.name.to_lowercase() == name.to_lowercase()
     {
-        println!("  found existing item");
+        info!("  found existing item");
         candidates[0].to_owned()
     } else if !candidates.is_empty() {
         println!("  found {} existing items:", candidates.len());
         for (i, item) in candidates.iter().enumerate() {
-            println!("  - [{}] {}", i + 1, item.name);
+            match &item.note {
+                Some(note) => println!("  - [{}] {} ({})", i + 1, item.name, short_note(note)),
+                None => println!("  - [{}] {}", i + 1, item.name),
+            }
         }
         print!("  enter number or leave empty to create new item, X to cancel: ");
         loop {
             let choice: String = read!("{}\n");
             if choice.is_empty() {
-                let item = create_item(None, &name)?;
-                println!("  created {item:?}");
+                let details = ItemDetails {
+                    category: prompt_category(None),
+                    ..Default::default()
+                };
+                let item = create_item(None, &name, &details, None)?;
+                info!("  created {item:?}");
                 break item;
             } else if choice.to_lowercase() == "x" {
                 anyhow::bail!("aborted");
@@ -177,20 +757,52 @@ fn create_custom() -> Result<()> {
         }
     } else {
         print!("  no existing item found, create new? [Y/n] ");
-        tcflush(0, TCIOFLUSH).unwrap();
+        flush_input();
         let s: String = read!("{}\n");
         if !s.is_empty() && s.to_lowercase() != "y" {
             anyhow::bail!("aborted");
         }
-        let item = create_item(None, &name)?;
-        println!("  created {item:?}");
+        let details = ItemDetails {
+            category: prompt_category(None),
+            ..Default::default()
+        };
+        let item = create_item(None, &name, &details, None)?;
+        info!("  created {item:?}");
         item
     };
+    let count = prompt_count()?;
+    let expiry = prompt_expiry();
+    info!("  adding to stock [{count}]");
+    let stock = add_to_stock(&item, count.into(), expiry, None)?;
+    // the stock is added already, so a failing query must not make it look like adding failed
+    let remaining = query_stock_summary(&item)
+        .ok()
+        .map(|summary| summary.available);
+    publish_change(&item, "added", remaining);
+    // all units share the same stock entry, so they also get the same label
+    let labels: Vec<_> = (0..count)
+        .map(|_| LabelContent::from_item_stock(&item, &stock))
+        .collect();
+    // the stock is there even without labels, so a printer problem must not undo adding it
+    if let Err(err) = print_labels(labels) {
+        error!("  queueing labels failed: {err}");
+        warn!("  use the reprint op to print them later");
+    }
+    Ok(StockChange {
+        op: ScanOp::Add,
+        item,
+        stock_ids: vec![stock.id],
+        quantity: count.into(),
+    })
+}
+
+fn prompt_count() -> Result<u8> {
     print!("  enter count [1]: ");
-    let count = loop {
+    flush_input();
+    loop {
         let resp: String = read!("{}\n");
         if resp.is_empty() {
-            break 1;
+            break Ok(1);
         } else {
             match resp.parse::<u8>() {
                 Err(err) => {
@@ -200,161 +812,1109 @@ fn create_custom() -> Result<()> {
                 Ok(0) => {
                     anyhow::bail!("nothing to add to stock");
                 }
-                Ok(count) => break count,
+                Ok(count) => break Ok(count),
             }
         };
+    }
+}
+
+/// Ask for the category of a new item, suggesting the one from openfoodfacts if available.
+fn prompt_category(suggestion: Option<String>) -> Option<String> {
+    match &suggestion {
+        Some(category) => print!("  enter category [{category}], X to clear: "),
+        None => print!("  enter category [none]: "),
+    }
+    flush_input();
+    let s: String = read!("{}\n");
+    let s = s.trim();
+    if s.is_empty() {
+        suggestion
+    } else if s.to_lowercase() == "x" {
+        None
+    } else {
+        Some(s.to_string())
+    }
+}
+
+fn merge() -> Result<()> {
+    info!("Merging items");
+    let source = prompt_item("  enter EAN or name of the item to merge away: ")?;
+    let target = prompt_item("  enter EAN or name of the item to merge into: ")?;
+    print!(
+        "  merge {} ({}) into {} ({})? [y/N] ",
+        source.name, source.id, target.name, target.id
+    );
+    flush_input();
+    let s: String = read!("{}\n");
+    if s.to_lowercase() != "y" {
+        anyhow::bail!("aborted");
+    }
+    merge_items(source.id, target.id, None)?;
+    info!("  successful");
+    Ok(())
+}
+
+fn remove_alias() -> Result<()> {
+    info!("Removing alias");
+    print!("  enter alias EAN, or EAN of the item to list its aliases: ");
+    flush_input();
+    let s: String = read!("{}\n");
+    let s = s.trim();
+    if s.is_empty() {
+        println!();
+        anyhow::bail!("nothing entered");
+    }
+    let item = query_item_by_ean(s)?.filter(|item| item.ean.as_deref() == Some(s));
+    let alias_ean = if let Some(item) = item {
+        let aliases = list_aliases_for(&item)?;
+        if aliases.is_empty() {
+            info!("  item has no aliases");
+            return Ok(());
+        }
+        println!("  found {} aliases:", aliases.len());
+        for (i, alias) in aliases.iter().enumerate() {
+            println!("  - [{}] {}", i + 1, alias.ean);
+        }
+        print!("  enter number to remove, leave empty to cancel: ");
+        loop {
+            let choice: String = read!("{}\n");
+            if choice.is_empty() {
+                anyhow::bail!("aborted");
+            }
+            match choice.parse::<usize>() {
+                Ok(idx) if idx > 0 && idx <= aliases.len() => break aliases[idx - 1].ean.clone(),
+                _ => print!("  invalid index, try again: "),
+            }
+        }
+    } else {
+        s.to_string()
     };
-    let mut conn = connect_db()?;
-    conn.transaction::<_, anyhow::Error, _>(|conn| {
-        let mut labels = Vec::<LabelContent>::with_capacity(count.into());
-        for i in 0..count {
-            println!("  adding to stock [{}/{}]", i + 1, count);
-            let stock = add_to_stock(&item, Some(conn))?;
-            labels.push(LabelContent::from_item_stock(&item, &stock));
-        }
-        print_custom_item_labels(&labels)
-    })?;
+    if delete_alias(&alias_ean, None)? {
+        info!("  removed alias {alias_ean}");
+    } else {
+        warn!("  {alias_ean} is not an alias");
+    }
     Ok(())
 }
 
-fn remove_custom(item_id: i32, stock_id: i32) -> Result<()> {
+fn prompt_item(prompt: &str) -> Result<Item> {
+    print!("{prompt}");
+    flush_input();
+    let s: String = read!("{}\n");
+    let s = s.trim();
+    if s.is_empty() {
+        println!();
+        anyhow::bail!("nothing entered");
+    }
+    let item = match query_item_by_ean(s)? {
+        Some(item) => Some(item),
+        None => query_item_by_name(s)?,
+    };
+    let item = item.ok_or_else(|| anyhow::anyhow!("no item found for '{s}'"))?;
+    info!("  found {item:?}");
+    Ok(item)
+}
+
+fn remove_custom(item_id: i32, stock_id: i32) -> Result<Option<StockChange>> {
     let item = match query_item_by_id(item_id)? {
         None => {
-            println!("Cannot remove custom item {item_id}, not found");
-            return Ok(());
+            warn!("Cannot remove custom item {item_id}, not found");
+            return Ok(None);
         }
         Some(item) => item,
     };
-    println!("Removing custom from stock: {}", item.name);
-    match remove_from_stock(&item, Some(stock_id))? {
-        Ok(_) => println!("  successful"),
-        Err(err) => println!("  {err}"),
+    info!("Removing custom from stock: {}", item.name);
+    let outcome = match remove_from_stock(&item, Some(stock_id), None)? {
+        Ok(stock_id) => OperationOutcome::Done {
+            remaining: Some(query_stock_summary(&item)?.available),
+            change: StockChange {
+                op: ScanOp::Remove,
+                item,
+                stock_ids: vec![stock_id],
+                quantity: 1,
+            },
+        },
+        Err(err) => OperationOutcome::Rejected(err),
+    };
+    Ok(report_outcome(outcome))
+}
+
+/// Handle the item label of a custom item. It is not tied to a stock entry, so any unit is used,
+/// and under ops that do not change stock it is removed, like when scanning a stock label.
+fn custom_item_scanned(op: ScanOp, item_id: i32) -> Result<Option<StockChange>> {
+    let Some(item) = query_item_by_id(item_id)? else {
+        warn!("Cannot use custom item {item_id}, not found");
+        return Ok(None);
+    };
+    let outcome = match op {
+        ScanOp::Add => add(item, None)?,
+        ScanOp::Open => open(item)?,
+        ScanOp::Finish => finish(item)?,
+        _ => remove(item)?,
+    };
+    Ok(report_outcome(outcome))
+}
+
+/// Parse a partial barcode entered by hand, e.g. `400638*`.
+fn parse_ean_prefix(line: &str) -> Option<&str> {
+    line.strip_suffix('*')
+        .filter(|prefix| !prefix.is_empty() && prefix.bytes().all(|c| c.is_ascii_digit()))
+}
+
+/// Let the user pick one of the items whose EAN starts with the given prefix.
+fn pick_by_ean_prefix(prefix: &str) -> Result<Option<String>> {
+    let candidates = query_items_by_ean_prefix(prefix)?;
+    if candidates.is_empty() {
+        warn!("No items found with EAN {prefix}*");
+        return Ok(None);
+    }
+    println!("  found {} items:", candidates.len());
+    for (i, item) in candidates.iter().enumerate() {
+        println!(
+            "  - [{}] {} ({})",
+            i + 1,
+            item.name,
+            item.ean.as_deref().unwrap_or_default()
+        );
+    }
+    print!("  enter number or leave empty to cancel: ");
+    flush_input();
+    loop {
+        let choice: String = read!("{}\n");
+        if choice.is_empty() {
+            println!();
+            return Ok(None);
+        }
+        match choice.parse::<usize>() {
+            Ok(idx) if idx > 0 && idx <= candidates.len() => {
+                return Ok(candidates[idx - 1].ean.clone());
+            }
+            Ok(_) => print!("  invalid index, try again: "),
+            Err(err) => print!("  invalid input ({err}), try again: "),
+        }
+    }
+}
+
+fn finish_custom(item_id: i32, stock_id: i32) -> Result<Option<StockChange>> {
+    let Some(item) = query_item_by_id(item_id)? else {
+        warn!("Cannot finish custom item {item_id}, not found");
+        return Ok(None);
+    };
+    info!("Finishing custom: {}", item.name);
+    let outcome = match finish_stock_by_id(&item, stock_id, None)? {
+        Ok(stock_id) => OperationOutcome::Done {
+            remaining: Some(query_stock_summary(&item)?.available),
+            change: StockChange {
+                op: ScanOp::Finish,
+                item,
+                stock_ids: vec![stock_id],
+                quantity: 1,
+            },
+        },
+        Err(err) => OperationOutcome::Rejected(err),
+    };
+    Ok(report_outcome(outcome))
+}
+
+fn reprint(item_id: i32, stock_id: i32) -> Result<()> {
+    let Some(item) = query_item_by_id(item_id)? else {
+        warn!("Cannot reprint label for custom item {item_id}, not found");
+        return Ok(());
+    };
+    info!("Reprinting label: {}", item.name);
+    let stock = match query_stock_by_id(stock_id)? {
+        Some(stock) if stock.item_id == item.id => stock,
+        _ => {
+            warn!("  stock entry {stock_id} not found");
+            return Ok(());
+        }
+    };
+    if stock.removed_dt.is_some() {
+        warn!("  stock entry {stock_id} has already been removed");
     }
+    print_labels(vec![LabelContent::from_item_stock(&item, &stock)])?;
+    info!("  successful");
     Ok(())
 }
 
-fn scanned(op: ScanOp, barcode: &str) -> Result<()> {
+fn move_custom(item_id: i32, stock_id: i32) -> Result<()> {
+    let Some(item) = query_item_by_id(item_id)? else {
+        warn!("Cannot move custom item {item_id}, not found");
+        return Ok(());
+    };
+    info!("Moving: {}", item.name);
+    match query_stock_by_id(stock_id)? {
+        Some(stock) if stock.item_id == item.id && stock.removed_dt.is_none() => move_stock(&stock),
+        _ => {
+            warn!("  stock entry {stock_id} not in stock");
+            Ok(())
+        }
+    }
+}
+
+fn undo(history: &mut Vec<StockChange>) -> Result<Option<StockChange>> {
+    let Some(change) = history.pop() else {
+        warn!("Nothing to undo");
+        return Ok(None);
+    };
+    info!("Undoing {:?}: {}", change.op, change.item.name);
+    let res = undo_change(&change);
+    if res.is_err() {
+        // keep it around so undoing can be retried
+        history.push(change);
+    }
+    match res? {
+        Ok(_) => {
+            info!("  successful");
+            let remaining = query_stock_summary(&change.item)
+                .ok()
+                .map(|summary| summary.available);
+            publish_change(&change.item, "undone", remaining);
+            Ok(Some(change))
+        }
+        Err(err) => {
+            warn!("  {err}");
+            Ok(None)
+        }
+    }
+}
+
+fn undo_change(change: &StockChange) -> Result<Result<()>> {
+    for &stock_id in &change.stock_ids {
+        let res = match change.op {
+            ScanOp::Add => undo_add(stock_id, None)?,
+            ScanOp::Remove | ScanOp::Finish => undo_remove(stock_id, None)?,
+            ScanOp::Open => undo_open(stock_id, None)?,
+            op => unreachable!("only stock changes are recorded, got {op:?}"),
+        };
+        if res.is_err() {
+            return Ok(res);
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Record a stock change so it shows up in the summary and can be undone. Returns whether
+/// there was a change.
+fn record_change(
+    res: Result<Option<StockChange>>,
+    summary: &mut SessionSummary,
+    history: &mut Vec<StockChange>,
+) -> bool {
+    match res {
+        Ok(Some(change)) => {
+            summary.record(&change);
+            history.push(change);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Let the operator know whether a scan succeeded without having to look at the screen.
+fn feedback(success: bool) {
+    let state = if success { "success" } else { "failure" };
+    if let Some(command) = &config().feedback_command {
+        // run it in the background so a slow buzzer does not delay the next scan
+        let res = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("LARDER_FEEDBACK", state)
+            .spawn();
+        match res {
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(err) => error!("running feedback command failed: {err}"),
+        }
+        return;
+    }
+    print!("\x07");
+    if !success {
+        std::io::stdout().flush().ok();
+        // a short pause so the terminal does not merge both bells into one
+        thread::sleep(Duration::from_millis(200));
+        print!("\x07");
+    }
+    std::io::stdout().flush().ok();
+}
+
+fn report_summary(summary: &SessionSummary) {
+    info!("Session summary:");
+    for (label, count) in [
+        ("registered", summary.registered),
+        ("added", summary.added),
+        ("removed", summary.removed),
+        ("opened", summary.opened),
+        ("finished", summary.finished),
+    ] {
+        if count > 0 {
+            info!("  {label}: {count}");
+        }
+    }
+}
+
+fn report_expiring() -> Result<()> {
+    report_overdue_open()?;
+    let expiring = query_expiring_soon(EXPIRY_WARNING_DAYS)?;
+    if expiring.is_empty() {
+        info!("Nothing expiring within {EXPIRY_WARNING_DAYS} days");
+        return Ok(());
+    }
+    info!("Expiring within {EXPIRY_WARNING_DAYS} days:");
+    // the query is ordered by expiry, so the item that expires first is also listed first
+    let mut grouped: Vec<(Item, Vec<Stock>)> = Vec::new();
+    for (item, stock) in expiring {
+        match grouped.iter_mut().find(|(it, _)| it.id == item.id) {
+            Some((_, entries)) => entries.push(stock),
+            None => grouped.push((item, vec![stock])),
+        }
+    }
+    for (item, entries) in grouped {
+        info!("  {}", item.name);
+        for stock in entries {
+            let expiry = stock.expiry_dt.expect("only stock with expiry is queried");
+            if stock.opened_dt.is_some() {
+                info!("  - {} (open)", expiry.format("%d.%m.%Y"));
+            } else {
+                info!("  - {}", expiry.format("%d.%m.%Y"));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn report_overdue_open() -> Result<()> {
+    let overdue = query_overdue_open()?;
+    if overdue.is_empty() {
+        return Ok(());
+    }
+    warn!("Open for too long:");
+    for (item, stock) in overdue {
+        let opened = stock.opened_dt.expect("only opened stock is queried");
+        warn!("  - {} (opened {})", item.name, opened.format("%d.%m.%Y"));
+    }
+    Ok(())
+}
+
+fn show_shopping_list() -> Result<()> {
+    for (entry, item) in query_shopping_list()? {
+        println!(
+            "{} (since {})",
+            item.name,
+            entry.added_dt.format("%d.%m.%Y")
+        );
+    }
+    Ok(())
+}
+
+/// Show how many units of each item were used per week recently, and how long the available
+/// stock lasts at that rate.
+fn show_stats(weeks: u32) -> Result<()> {
+    let since = Local::now() - chrono::TimeDelta::weeks(weeks.into());
+    let consumption = query_consumption(since)?;
+    if consumption.is_empty() {
+        println!("Nothing was used in the last {weeks} weeks");
+        return Ok(());
+    }
+    let width = consumption
+        .iter()
+        .map(|(item, _)| item.name.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!(
+        "{:<width$}  {:>6}  {:>8}  {:>9}  {:>10}",
+        "Item", "Used", "Per week", "Available", "Weeks left"
+    );
+    for (item, used) in consumption {
+        let per_week = used as f64 / f64::from(weeks);
+        let available = query_stock_summary(&item)?.available;
+        let weeks_left = available as f64 / per_week;
+        println!(
+            "{:<width$}  {used:>6}  {per_week:>8.1}  {available:>9}  {weeks_left:>10.1}",
+            item.name
+        );
+    }
+    Ok(())
+}
+
+/// Print labels that are not tied to a stock entry, e.g. for a batch that is not in stock yet.
+fn print_item_labels(name: &str, count: u8) -> Result<()> {
+    let item = query_item_by_name(name)?.ok_or_else(|| anyhow::anyhow!("No item named {name}"))?;
+    let labels: Vec<_> = (0..count).map(|_| LabelContent::from_item(&item)).collect();
+    // the labels must be printed before the process exits, so the print queue cannot be used
+    print_custom_item_labels(&labels)
+}
+
+fn print_shopping_list_label() -> Result<()> {
+    let names: Vec<_> = query_shopping_list()?
+        .into_iter()
+        .map(|(_, item)| item.name)
+        .collect();
+    if names.is_empty() {
+        info!("Shopping list is empty");
+        return Ok(());
+    }
+    info!("Shopping list:");
+    for name in &names {
+        info!("  - {name}");
+    }
+    print_shopping_list(names)
+}
+
+fn report_low_stock() -> Result<()> {
+    let low = query_low_stock()?;
+    if low.is_empty() {
+        return Ok(());
+    }
+    warn!("Running low on {} items:", low.len());
+    for (item, available) in low {
+        let min = item
+            .min_stock
+            .expect("only items with minimum stock are queried");
+        warn!("  - {}: {available}/{min}", item.name);
+    }
+    Ok(())
+}
+
+fn report_inconsistencies() -> Result<()> {
+    let problems = check_consistency()?;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    warn!("Found {} data problems:", problems.len());
+    for problem in problems {
+        warn!("  - {problem}");
+    }
+    Ok(())
+}
+
+fn scanned(
+    op: ScanOp,
+    barcode: &str,
+    expiry: Option<NaiveDate>,
+    summary: &mut SessionSummary,
+) -> Result<Option<StockChange>> {
+    // a misread must not ring the success bell, even when nothing would have been changed
+    if validate_ean(barcode).is_none() {
+        anyhow::bail!("{barcode} is not a valid EAN (misread?)");
+    }
     let mut existing = query_item_by_ean(barcode)?;
     match op {
         ScanOp::None => {
             match existing {
                 Some(item) => {
-                    println!("Item found {item:?}");
-                    let stock_info = query_item_stock(item.id)?;
-                    if stock_info.opened == 0 {
-                        println!("  stock: {}", stock_info.unopened);
+                    info!("Item found {item:?}");
+                    let summary = query_stock_summary(&item)?;
+                    if summary.opened == 0 {
+                        info!("  stock: {}", summary.available);
                     } else {
-                        println!(
-                            "  stock: {} new + {} open",
-                            stock_info.unopened, stock_info.opened
+                        info!(
+                            "  stock: {} ({} new + {} open)",
+                            summary.total, summary.available, summary.opened
                         )
                     };
+                    if let Some(oldest) = summary.oldest_added_dt {
+                        info!("  oldest: {}", oldest.format("%d.%m.%Y"));
+                    }
+                    if let Some(note) = &item.note {
+                        info!("  note: {note}");
+                    }
                 }
                 None => {
-                    println!("No such item: {barcode}");
-                    if let Some(off_name) = lookup(barcode)? {
-                        println!("  found on openfoodfacts: {off_name}");
+                    warn!("No such item: {barcode}");
+                    if let Some(product) = lookup(barcode)? {
+                        info!("  found on openfoodfacts: {}", product.name);
                     } else {
-                        println!("  not on openfoodfacts")
+                        warn!("  not on openfoodfacts")
                     }
+                    anyhow::bail!("no item found");
                 }
             };
+            Ok(None)
         }
         ScanOp::Register => {
-            register(barcode, existing)?;
+            if register(barcode, existing)?.is_none() {
+                anyhow::bail!("nothing registered");
+            }
+            summary.record_registration();
+            Ok(None)
         }
         ScanOp::Add => {
             if existing.is_none() {
-                println!("Trying to add {barcode}, but no item found");
+                warn!("Trying to add {barcode}, but no item found");
+                if config().confirm_auto_register {
+                    print!("  register it now? [Y/n] ");
+                    flush_input();
+                    let s: String = read!("{}\n");
+                    if !s.is_empty() && s.to_lowercase() != "y" {
+                        warn!("  no item added");
+                        return Ok(None);
+                    }
+                }
                 existing = register(barcode, existing)?;
                 if existing.is_none() {
-                    println!("  no item added");
-                    return Ok(());
+                    warn!("  no item added");
+                    return Ok(None);
                 }
+                summary.record_registration();
             }
-            add(existing.unwrap())?;
+            add(existing.unwrap(), expiry).map(report_outcome)
         }
         ScanOp::Remove => {
             if existing.is_none() {
-                println!("Cannot remove {barcode}, no item found");
-                return Ok(());
+                warn!("Cannot remove {barcode}, no item found");
+                return Ok(None);
             }
-            remove(existing.unwrap())?;
+            remove(existing.unwrap()).map(report_outcome)
         }
         ScanOp::Open => {
             if existing.is_none() {
-                println!("Cannot open {barcode}, no item found");
-                return Ok(());
+                warn!("Cannot open {barcode}, no item found");
+                return Ok(None);
             }
-            open(existing.unwrap())?;
+            open(existing.unwrap()).map(report_outcome)
         }
         ScanOp::Finish => {
             if existing.is_none() {
-                println!("Cannot finish {barcode}, no item found");
-                return Ok(());
+                warn!("Cannot finish {barcode}, no item found");
+                return Ok(None);
+            }
+            finish(existing.unwrap()).map(report_outcome)
+        }
+        ScanOp::MinStock => {
+            if existing.is_none() {
+                anyhow::bail!("cannot set minimum stock of {barcode}, no item found");
+            }
+            update_min_stock(existing.unwrap())?;
+            Ok(None)
+        }
+        ScanOp::OpenShelfDays => {
+            if existing.is_none() {
+                anyhow::bail!("cannot set open shelf life of {barcode}, no item found");
+            }
+            update_open_shelf_days(existing.unwrap())?;
+            Ok(None)
+        }
+        ScanOp::Remaining => {
+            if existing.is_none() {
+                anyhow::bail!("cannot set remaining amount of {barcode}, no item found");
             }
-            finish(existing.unwrap())?;
+            update_remaining(existing.unwrap())?;
+            Ok(None)
+        }
+        ScanOp::Note => {
+            if existing.is_none() {
+                anyhow::bail!("cannot set note of {barcode}, no item found");
+            }
+            update_note(existing.unwrap())?;
+            Ok(None)
+        }
+        ScanOp::Rename => {
+            if existing.is_none() {
+                anyhow::bail!("cannot rename {barcode}, no item found");
+            }
+            rename(existing.unwrap())?;
+            Ok(None)
+        }
+        ScanOp::Reprint => {
+            anyhow::bail!("cannot reprint {barcode}, only custom labels can be reprinted");
+        }
+        ScanOp::Move => {
+            if existing.is_none() {
+                anyhow::bail!("cannot move {barcode}, no item found");
+            }
+            move_item(existing.unwrap())?;
+            Ok(None)
+        }
+        ScanOp::SetCount => {
+            if existing.is_none() {
+                anyhow::bail!("cannot set stock count of {barcode}, no item found");
+            }
+            update_count(existing.unwrap())?;
+            Ok(None)
+        }
+        ScanOp::Count => {
+            // nothing is written here, not even the openfoodfacts cache
+            match existing {
+                Some(item) => report_count(&item)?,
+                None => anyhow::bail!("no item found"),
+            }
+            Ok(None)
+        }
+        ScanOp::Delete => {
+            if existing.is_none() {
+                anyhow::bail!("cannot delete {barcode}, no item found");
+            }
+            delete(existing.unwrap())?;
+            Ok(None)
+        }
+    }
+}
+
+/// Tell the event clients about a stock change.
+fn publish_change(item: &Item, action: &str, remaining: Option<i64>) {
+    events::publish(&Event {
+        item_id: item.id,
+        item: &item.name,
+        action,
+        timestamp: Local::now(),
+        remaining,
+    });
+}
+
+fn report_outcome(outcome: OperationOutcome) -> Option<StockChange> {
+    match outcome {
+        OperationOutcome::Done { change, remaining } => {
+            let done = match change.op {
+                ScanOp::Add => "added",
+                ScanOp::Remove => "removed",
+                ScanOp::Open => "opened",
+                ScanOp::Finish => "finished",
+                op => unreachable!("only stock changes are reported, got {op:?}"),
+            };
+            match remaining {
+                Some(remaining) => info!("  {done}, {remaining} left"),
+                None => info!("  {done}"),
+            }
+            publish_change(&change.item, done, remaining);
+            Some(change)
+        }
+        OperationOutcome::Rejected(err) => {
+            warn!("  {err}");
+            None
+        }
+    }
+}
+
+fn add(item: Item, expiry: Option<NaiveDate>) -> Result<OperationOutcome> {
+    info!("Adding to stock: {}", item.name);
+    let count = prompt_count()?;
+    let expiry = match expiry {
+        Some(expiry) => {
+            info!("  expiry from barcode: {}", expiry.format("%d.%m.%Y"));
+            Some(expiry)
+        }
+        None => prompt_expiry(),
+    };
+    let stock = add_to_stock(&item, count.into(), expiry, None)?;
+    let labels: Vec<_> = (0..count)
+        .map(|_| LabelContent::from_item_stock(&item, &stock))
+        .collect();
+    if config().print_bought_labels
+        && let Err(err) = print_labels(labels)
+    {
+        // the item is in stock anyway, and the label can still be reprinted later
+        error!("  queueing label failed: {err}");
+        warn!("  use the reprint op to print it later");
+    }
+    Ok(OperationOutcome::Done {
+        remaining: Some(query_stock_summary(&item)?.available),
+        change: StockChange {
+            op: ScanOp::Add,
+            item,
+            stock_ids: vec![stock.id],
+            quantity: count.into(),
+        },
+    })
+}
+
+fn prompt_expiry() -> Option<NaiveDate> {
+    print!("  enter expiry date [none]: ");
+    flush_input();
+    loop {
+        let resp: String = read!("{}\n");
+        if resp.is_empty() {
+            break None;
+        }
+        match parse_expiry(&resp) {
+            Some(date) => break Some(date),
+            None => print!("  invalid date (use MM/YY or DD.MM.YYYY), try again: "),
+        }
+    }
+}
+
+fn parse_expiry(s: &str) -> Option<NaiveDate> {
+    let s = s.trim();
+    // try the short year first, since `%Y` would happily accept "25" as the year 25 AD
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%d.%m.%y")
+        .or_else(|_| NaiveDate::parse_from_str(s, "%d.%m.%Y"))
+    {
+        return Some(date);
+    }
+    // a best-before date without a day means the end of that month
+    let (month, year) = s.split_once('/')?;
+    let month: u32 = month.trim().parse().ok()?;
+    let year: i32 = year.trim().parse().ok()?;
+    let year = if year < 100 { 2000 + year } else { year };
+    NaiveDate::from_ymd_opt(year, month, 1)?
+        .checked_add_months(Months::new(1))?
+        .pred_opt()
+}
+
+fn remove(item: Item) -> Result<OperationOutcome> {
+    info!("Removing from stock: {}", item.name);
+    match remove_from_stock(&item, None, None)? {
+        Ok(stock_id) => Ok(OperationOutcome::Done {
+            remaining: Some(query_stock_summary(&item)?.available),
+            change: StockChange {
+                op: ScanOp::Remove,
+                item,
+                stock_ids: vec![stock_id],
+                quantity: 1,
+            },
+        }),
+        Err(err) => Ok(OperationOutcome::Rejected(err)),
+    }
+}
+
+fn open(item: Item) -> Result<OperationOutcome> {
+    info!("Opening: {}", item.name);
+    // opening a whole batch may open several units, which is easiest to tell from the count
+    let available = query_stock_summary(&item)?.available;
+    match open_from_stock(&item, None)? {
+        Ok(stock_ids) => {
+            let remaining = query_stock_summary(&item)?.available;
+            Ok(OperationOutcome::Done {
+                remaining: Some(remaining),
+                change: StockChange {
+                    op: ScanOp::Open,
+                    item,
+                    stock_ids,
+                    quantity: (available - remaining).max(1) as u32,
+                },
+            })
         }
+        Err(err) => Ok(OperationOutcome::Rejected(err)),
+    }
+}
+
+fn finish(item: Item) -> Result<OperationOutcome> {
+    info!("Finishing: {}", item.name);
+    match finish_from_stock(&item, None)? {
+        Ok(stock_id) => Ok(OperationOutcome::Done {
+            remaining: Some(query_stock_summary(&item)?.available),
+            change: StockChange {
+                op: ScanOp::Finish,
+                item,
+                stock_ids: vec![stock_id],
+                quantity: 1,
+            },
+        }),
+        Err(err) => Ok(OperationOutcome::Rejected(err)),
+    }
+}
+
+fn update_min_stock(item: Item) -> Result<()> {
+    info!("Setting minimum stock: {}", item.name);
+    match item.min_stock {
+        Some(min) => print!("  enter minimum stock [{min}], X to clear: "),
+        None => print!("  enter minimum stock [none]: "),
     }
+    flush_input();
+    let min = loop {
+        let resp: String = read!("{}\n");
+        if resp.is_empty() {
+            info!("  unchanged");
+            return Ok(());
+        } else if resp.to_lowercase() == "x" {
+            break None;
+        }
+        match resp.parse::<u16>() {
+            Ok(min) => break Some(min.into()),
+            Err(err) => print!("  invalid input ({err}), try again: "),
+        }
+    };
+    set_min_stock(item.id, min, None)?;
+    info!("  successful");
     Ok(())
 }
 
-fn add(item: Item) -> Result<Stock> {
-    println!("Adding to stock: {}", item.name);
-    let res = add_to_stock(&item, None);
-    match res {
-        Ok(_) => println!("  successful"),
-        Err(ref err) => println!("  {err}"),
+fn update_open_shelf_days(item: Item) -> Result<()> {
+    info!("Setting open shelf life: {}", item.name);
+    match item.open_shelf_days {
+        Some(days) => print!("  enter days it keeps once opened [{days}], X to clear: "),
+        None => print!("  enter days it keeps once opened [none]: "),
     }
-    res
+    flush_input();
+    let days = loop {
+        let resp: String = read!("{}\n");
+        if resp.is_empty() {
+            info!("  unchanged");
+            return Ok(());
+        } else if resp.to_lowercase() == "x" {
+            break None;
+        }
+        match resp.parse::<u16>() {
+            Ok(days) => break Some(days.into()),
+            Err(err) => print!("  invalid input ({err}), try again: "),
+        }
+    };
+    set_open_shelf_days(item.id, days, None)?;
+    info!("  successful");
+    Ok(())
 }
 
-fn remove(item: Item) -> Result<()> {
-    println!("Removing from stock: {}", item.name);
-    match remove_from_stock(&item, None)? {
-        Ok(_) => {
-            println!("  successful");
-            let stock_info = query_item_stock(item.id)?;
-            println!("  remaining: {}", stock_info.unopened)
+fn update_remaining(item: Item) -> Result<()> {
+    info!("Setting remaining amount: {}", item.name);
+    print!("  enter how much is left (e.g. 0.5, 50% or 1/2): ");
+    flush_input();
+    let fraction = loop {
+        let resp: String = read!("{}\n");
+        if resp.is_empty() {
+            info!("  unchanged");
+            return Ok(());
+        }
+        match parse_fraction(&resp) {
+            Some(fraction) => break fraction,
+            None => print!("  invalid input, try again: "),
+        }
+    };
+    match set_remaining_fraction(&item, fraction, None)? {
+        Ok(_) => info!("  successful"),
+        Err(err) => warn!("  cannot set remaining amount: {err}"),
+    }
+    Ok(())
+}
+
+/// Parse an amount like `0.5`, `50%` or `1/2` into a fraction between 0 and 1.
+fn parse_fraction(input: &str) -> Option<f32> {
+    let input = input.trim();
+    let fraction = if let Some(percent) = input.strip_suffix('%') {
+        percent.trim().parse::<f32>().ok()? / 100.0
+    } else if let Some((num, denom)) = input.split_once('/') {
+        num.trim().parse::<f32>().ok()? / denom.trim().parse::<f32>().ok()?
+    } else {
+        input.parse().ok()?
+    };
+    // this also rejects NaN, e.g. from 0/0
+    (0.0..=1.0).contains(&fraction).then_some(fraction)
+}
+
+fn report_count(item: &Item) -> Result<()> {
+    let summary = query_stock_summary(item)?;
+    info!("{}", item.name);
+    info!("  total: {}", summary.total);
+    info!("  available: {}", summary.available);
+    if summary.opened_remaining < summary.opened as f64 {
+        info!(
+            "  open: {} (about {:.1} left)",
+            summary.opened, summary.opened_remaining
+        );
+    } else {
+        info!("  open: {}", summary.opened);
+    }
+    if let Some(oldest) = summary.oldest_added_dt {
+        info!("  oldest: {}", oldest.format("%d.%m.%Y"));
+    }
+    if let Some(expiry) = summary.next_expiry_dt {
+        info!("  next expiry: {}", expiry.format("%d.%m.%Y"));
+    }
+    Ok(())
+}
+
+fn update_count(item: Item) -> Result<()> {
+    info!("Setting stock count: {}", item.name);
+    let summary = query_stock_summary(&item)?;
+    print!("  enter available count [{}]: ", summary.available);
+    flush_input();
+    let target = loop {
+        let resp: String = read!("{}\n");
+        if resp.is_empty() {
+            info!("  unchanged");
+            return Ok(());
+        }
+        match resp.parse::<u16>() {
+            Ok(target) => break target,
+            Err(err) => print!("  invalid input ({err}), try again: "),
+        }
+    };
+    let previous = set_stock_count(&item, target.into(), None)?;
+    info!("  changed from {previous} to {target}");
+    if previous != i64::from(target) {
+        publish_change(&item, "counted", Some(target.into()));
+    }
+    Ok(())
+}
+
+fn update_note(item: Item) -> Result<()> {
+    info!("Setting note: {}", item.name);
+    match &item.note {
+        Some(note) => print!("  enter note [{note}], X to clear: "),
+        None => print!("  enter note: "),
+    }
+    flush_input();
+    let resp: String = read!("{}\n");
+    let note = match resp.trim() {
+        "" => {
+            info!("  unchanged");
+            return Ok(());
         }
-        Err(err) => println!("  {err}"),
+        "x" | "X" => None,
+        note => Some(note),
+    };
+    set_note(item.id, note, None)?;
+    info!("  successful");
+    Ok(())
+}
+
+/// Shorten a note so it fits into a list of items.
+fn short_note(note: &str) -> String {
+    const MAX_LEN: usize = 30;
+    if note.chars().count() <= MAX_LEN {
+        return note.to_string();
+    }
+    let mut short: String = note.chars().take(MAX_LEN - 1).collect();
+    short.push('…');
+    short
+}
+
+fn rename(item: Item) -> Result<()> {
+    info!("Renaming: {}", item.name);
+    print!("  enter new name: ");
+    flush_input();
+    let name: String = read!("{}\n");
+    let name = name.trim();
+    if name.is_empty() {
+        println!();
+        anyhow::bail!("no name provided");
+    }
+    if let Some(other) = query_item_by_name(name)?
+        && other.id != item.id
+    {
+        let other_desc = other.ean.unwrap_or_else(|| "custom item".to_string());
+        warn!("  name collision with {other_desc}, not renaming");
+        return Ok(());
     }
+    let item = rename_item(item.id, name, None)?;
+    info!("  renamed to {}", item.name);
     Ok(())
 }
 
-fn open(item: Item) -> Result<()> {
-    println!("Opening: {}", item.name);
-    match open_from_stock(&item)? {
-        Ok(_) => println!("  successful"),
-        Err(err) => println!("  {err}"),
+fn move_item(item: Item) -> Result<()> {
+    info!("Moving: {}", item.name);
+    let entries = query_stock_entries(&item)?;
+    let stock = match entries.as_slice() {
+        [] => {
+            warn!("  item not in stock");
+            return Ok(());
+        }
+        [stock] => stock,
+        _ => {
+            println!("  found {} stock entries:", entries.len());
+            for (i, stock) in entries.iter().enumerate() {
+                println!(
+                    "  - [{}] {}x from {}{} in {}",
+                    i + 1,
+                    stock.quantity,
+                    stock.added_dt.format("%d.%m.%Y"),
+                    if stock.opened_dt.is_some() {
+                        " (open)"
+                    } else {
+                        ""
+                    },
+                    stock.location.as_deref().unwrap_or("unknown location")
+                );
+            }
+            print!("  enter number: ");
+            flush_input();
+            loop {
+                let choice: String = read!("{}\n");
+                if choice.is_empty() {
+                    anyhow::bail!("aborted");
+                }
+                match choice.parse::<usize>() {
+                    Ok(idx) if idx > 0 && idx <= entries.len() => break &entries[idx - 1],
+                    _ => print!("  invalid index, try again: "),
+                }
+            }
+        }
+    };
+    move_stock(stock)
+}
+
+fn move_stock(stock: &Stock) -> Result<()> {
+    match &stock.location {
+        Some(location) => print!("  enter new location [{location}], X to clear: "),
+        None => print!("  enter new location: "),
     }
+    flush_input();
+    let s: String = read!("{}\n");
+    let s = s.trim();
+    let location = if s.is_empty() {
+        println!();
+        info!("  unchanged");
+        return Ok(());
+    } else if s.to_lowercase() == "x" {
+        None
+    } else {
+        Some(s)
+    };
+    update_stock_location(stock.id, location, None)?;
+    info!("  moved to {}", location.unwrap_or("unknown location"));
     Ok(())
 }
 
-fn finish(item: Item) -> Result<()> {
-    println!("Finishing: {}", item.name);
-    match finish_from_stock(&item)? {
-        Ok(_) => println!("  successful"),
-        Err(err) => println!("  {err}"),
+fn delete(item: Item) -> Result<()> {
+    info!("Deleting: {}", item.name);
+    // deleted items are ignored when looking them up, so their stock could not be removed anymore
+    let summary = query_stock_summary(&item)?;
+    if summary.total > 0 {
+        anyhow::bail!("still in stock: {}, remove it first", summary.total);
     }
+    print!("  delete {}? [y/N] ", item.name);
+    flush_input();
+    let s: String = read!("{}\n");
+    if s.to_lowercase() != "y" {
+        anyhow::bail!("aborted");
+    }
+    delete_item(item.id, None)?;
+    info!("  successful");
     Ok(())
 }
 
 fn register(barcode: &str, existing: Option<Item>) -> Result<Option<Item>> {
-    println!("Registering {barcode}");
+    info!("Registering {barcode}");
     if let Some(item) = existing {
-        println!("  already registered ({})", item.name);
+        warn!("  already registered ({})", item.name);
         return Ok(None);
     }
-    println!("  looking up name via openfoodfacts");
-    let name = lookup(barcode)?
-        .map(|n| {
-            println!(r#"  found "{n}""#);
-            n.to_string()
+    if let Some(item) = query_deleted_item_by_ean(barcode)? {
+        print!("  found deleted item {} - restore it? [Y/n] ", item.name);
+        flush_input();
+        let s: String = read!("{}\n");
+        if s.is_empty() || s.to_lowercase() == "y" {
+            let item = undelete_item(item.id, None)?;
+            info!("  restored {item:?}");
+            return Ok(Some(item));
+        }
+    }
+    let product = if config().off_lookup {
+        info!("  looking up name via openfoodfacts");
+        lookup(barcode).unwrap_or_else(|err| {
+            warn!("  lookup failed: {err}");
+            None
+        })
+    } else {
+        None
+    };
+    let mut details = product
+        .as_ref()
+        .map(|p| p.details.clone())
+        .unwrap_or_default();
+    let name = product
+        .map(|p| {
+            info!(r#"  found "{}""#, p.name);
+            p.name
         })
         .or_else(|| {
-            print!("  nothing found, enter manually: ");
-            tcflush(0, TCIOFLUSH).unwrap();
+            if config().off_lookup {
+                print!("  nothing found, enter manually: ");
+            } else {
+                print!("  enter name: ");
+            }
+            flush_input();
             let s: String = read!("{}\n");
             if s.is_empty() {
                 println!();
@@ -373,45 +1933,25 @@ fn register(barcode: &str, existing: Option<Item>) -> Result<Option<Item>> {
         })
         .ok_or(anyhow::anyhow!("no name provided"))?;
 
-    if let Some(item) = query_item_by_name(&name)? {
-        let conflict_ean = item
-            .ean
-            .clone()
-            .ok_or_else(|| anyhow::anyhow!("name collision with custom item"))?;
-        print!("  name collision with {conflict_ean} - create alias? [Y/n] ");
-        tcflush(0, TCIOFLUSH).unwrap();
-        let s: String = read!("{}\n");
-        if !s.is_empty() && s.to_lowercase() != "y" {
-            anyhow::bail!("Unresolved name conflict");
+    match query_item_by_name(&name)? {
+        Some(item) => {
+            let conflict_ean = item
+                .ean
+                .ok_or_else(|| anyhow::anyhow!("name collision with custom item"))?;
+            print!("  name collision with {conflict_ean} - create alias? [Y/n] ");
+            flush_input();
+            let s: String = read!("{}\n");
+            if !s.is_empty() && s.to_lowercase() != "y" {
+                anyhow::bail!("Unresolved name conflict");
+            }
         }
-        create_alias(barcode, &conflict_ean)?;
-        println!("  alias created");
-        return Ok(Some(item));
+        None => details.category = prompt_category(details.category.take()),
     }
-
-    let item = create_item(Some(barcode), &name)?;
-    println!("  created {item:?}");
-    Ok(Some(item))
-}
-
-fn lookup(ean: &str) -> Result<Option<String>> {
-    if ean == "4061463732958" {
-        // wrong data in off, it's aldi kleenex and not bread...
-        return Ok(None);
-    }
-    let client = off::v0().build().unwrap();
-    let settings = Some(Output::new().fields("product_name,product_name_de"));
-    let response = client
-        .product(ean, settings)
-        .map_err(|err| anyhow::anyhow!("Could not load product: {err}"))?;
-    let data = json!(response.json::<HashMap::<String, Value>>()?);
-    if data["status"].as_i64().unwrap_or(0) != 1 {
-        return Ok(None);
+    let (item, created) = ensure_item(barcode, &name, &details, None)?;
+    if created {
+        info!("  created {item:?}");
+    } else {
+        info!("  alias created");
     }
-    data["product"]["product_name_de"]
-        .as_str()
-        .filter(|n| !n.is_empty())
-        .or(data["product"]["product_name"].as_str())
-        .map(|n| Some(n.into()))
-        .ok_or(anyhow::anyhow!("Product has no name"))
+    Ok(Some(item))
 }
@@ -0,0 +1,55 @@
+use anyhow::Result;
+use prometheus::{Encoder, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use std::sync::LazyLock;
+
+use crate::db::{count_items, query_stock_totals};
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+static OPERATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    let counter = IntCounterVec::new(
+        Opts::new("larder_operations_total", "Number of stock operations"),
+        &["op"],
+    )
+    .expect("Metric definition is valid");
+    REGISTRY
+        .register(Box::new(counter.clone()))
+        .expect("Metric is only registered once");
+    counter
+});
+
+static ITEMS: LazyLock<IntGauge> =
+    LazyLock::new(|| register_gauge("larder_items_total", "Number of known items"));
+
+static AVAILABLE: LazyLock<IntGauge> =
+    LazyLock::new(|| register_gauge("larder_stock_available", "Number of unopened units"));
+
+static OPENED: LazyLock<IntGauge> =
+    LazyLock::new(|| register_gauge("larder_stock_open", "Number of opened units"));
+
+fn register_gauge(name: &str, help: &str) -> IntGauge {
+    let gauge = IntGauge::new(name, help).expect("Metric definition is valid");
+    REGISTRY
+        .register(Box::new(gauge.clone()))
+        .expect("Metric is only registered once");
+    gauge
+}
+
+/// Count an operation, e.g. `add` or `register`.
+pub fn record_operation(op: &str) {
+    OPERATIONS.with_label_values(&[op]).inc();
+}
+
+/// Get all metrics in the Prometheus text format. The stock counts are queried on every call.
+pub fn gather() -> Result<String> {
+    let (available, opened) = query_stock_totals()?;
+    ITEMS.set(count_items(false)?);
+    AVAILABLE.set(available);
+    OPENED.set(opened);
+    // make sure the counters show up even before the first operation
+    LazyLock::force(&OPERATIONS);
+
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
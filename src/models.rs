@@ -1,23 +1,35 @@
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, NaiveDate};
 use diesel::prelude::*;
-use diesel::sql_types::Text;
+use diesel::sql_types::{Nullable, Text};
 
-use crate::schema::{aliases, items, stock};
+use crate::schema::{item_eans, items, off_cache, shopping_list, stock, stock_log};
 use diesel::deserialize::{FromSql, FromSqlRow};
 use diesel::expression::AsExpression;
 use diesel::pg::{Pg, PgValue};
 use diesel::serialize::{IsNull, Output, ToSql};
 use diesel::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
 use std::io::Write;
 
-#[derive(Debug, Clone, FromSqlRow, AsExpression, PartialEq, Eq)]
+#[derive(Debug, Clone, FromSqlRow, AsExpression, PartialEq, Eq, Serialize, Deserialize)]
 #[diesel(sql_type = crate::schema::sql_types::ItemKind)]
+#[serde(rename_all = "lowercase")]
 pub enum ItemKind {
     Bought,
     Custom,
 }
 
-#[derive(Debug, Clone, Queryable, Selectable)]
+#[derive(Debug, Clone, Copy, FromSqlRow, AsExpression, PartialEq, Eq, Serialize, Deserialize)]
+#[diesel(sql_type = crate::schema::sql_types::StockAction)]
+#[serde(rename_all = "lowercase")]
+pub enum StockAction {
+    Add,
+    Remove,
+    Open,
+    Finish,
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
 #[diesel(table_name = items)]
 #[allow(dead_code)]
 pub struct Item {
@@ -25,6 +37,23 @@ pub struct Item {
     pub name: String,
     pub kind: ItemKind,
     pub ean: Option<String>,
+    pub min_stock: Option<i32>,
+    pub brand: Option<String>,
+    pub quantity: Option<String>,
+    pub category: Option<String>,
+    pub deleted_dt: Option<DateTime<Local>>,
+    /// How many days the item keeps once opened.
+    pub open_shelf_days: Option<i32>,
+    /// A free-text note, e.g. where to buy it.
+    pub note: Option<String>,
+}
+
+/// Optional product information of an item.
+#[derive(Debug, Clone, Default)]
+pub struct ItemDetails {
+    pub brand: Option<String>,
+    pub quantity: Option<String>,
+    pub category: Option<String>,
 }
 
 #[derive(Debug, Insertable)]
@@ -33,17 +62,30 @@ pub struct NewItem<'a> {
     pub name: &'a str,
     pub kind: ItemKind,
     pub ean: Option<&'a str>,
+    pub brand: Option<&'a str>,
+    pub quantity: Option<&'a str>,
+    pub category: Option<&'a str>,
+}
+
+/// An additional EAN of an item, e.g. for a different package size of the same product.
+#[derive(Debug, Clone, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = item_eans)]
+#[allow(dead_code)]
+pub struct ItemEan {
+    pub ean: String,
+    pub item_id: i32,
 }
 
-#[derive(Debug, Queryable, Selectable, Insertable)]
-#[diesel(table_name = aliases)]
+/// An alias in a backup made before items could have several EANs. It points to the EAN of an
+/// item instead of the item itself.
+#[derive(Debug, Deserialize)]
 #[allow(dead_code)]
-pub struct Alias {
+pub struct LegacyAlias {
     pub ean: String,
     pub alias_for: String,
 }
 
-#[derive(Debug, Queryable, Selectable)]
+#[derive(Debug, Queryable, Selectable, Insertable, Serialize, Deserialize)]
 #[diesel(table_name = stock)]
 #[allow(dead_code)]
 pub struct Stock {
@@ -52,6 +94,59 @@ pub struct Stock {
     pub added_dt: DateTime<Local>,
     pub opened_dt: Option<DateTime<Local>>,
     pub removed_dt: Option<DateTime<Local>>,
+    pub expiry_dt: Option<NaiveDate>,
+    pub quantity: i32,
+    pub location: Option<String>,
+    /// How much of an opened unit is left, from 0 to 1, if known.
+    pub remaining_fraction: Option<f32>,
+}
+
+/// All items, their additional EANs, stock with its history and the shopping list, e.g. to move
+/// them to a different database.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    pub items: Vec<Item>,
+    #[serde(default)]
+    pub eans: Vec<ItemEan>,
+    #[serde(default, skip_serializing)]
+    pub aliases: Vec<LegacyAlias>,
+    pub stock: Vec<Stock>,
+    #[serde(default)]
+    pub stock_log: Vec<StockLogEntry>,
+    #[serde(default)]
+    pub shopping_list: Vec<ShoppingListEntry>,
+}
+
+#[derive(Debug, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = stock_log)]
+#[allow(dead_code)]
+pub struct StockLogEntry {
+    pub id: i32,
+    pub stock_id: i32,
+    pub action: StockAction,
+    pub occurred_dt: DateTime<Local>,
+}
+
+#[derive(Debug, Queryable, Selectable, Insertable, Serialize, Deserialize)]
+#[diesel(table_name = shopping_list)]
+#[allow(dead_code)]
+pub struct ShoppingListEntry {
+    pub id: i32,
+    pub item_id: i32,
+    pub added_dt: DateTime<Local>,
+    pub removed_dt: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Queryable, Selectable)]
+#[diesel(table_name = off_cache)]
+#[allow(dead_code)]
+pub struct OffCacheEntry {
+    pub ean: String,
+    pub name: Option<String>,
+    pub fetched_dt: DateTime<Local>,
+    pub brand: Option<String>,
+    pub quantity: Option<String>,
+    pub category: Option<String>,
 }
 
 impl ToSql<crate::schema::sql_types::ItemKind, Pg> for ItemKind {
@@ -78,4 +173,35 @@ impl FromSql<crate::schema::sql_types::ItemKind, Pg> for ItemKind {
     }
 }
 
+impl ToSql<crate::schema::sql_types::StockAction, Pg> for StockAction {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        match *self {
+            StockAction::Add => out.write_all(b"add")?,
+            StockAction::Remove => out.write_all(b"remove")?,
+            StockAction::Open => out.write_all(b"open")?,
+            StockAction::Finish => out.write_all(b"finish")?,
+        }
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<crate::schema::sql_types::StockAction, Pg> for StockAction {
+    fn from_sql(bytes: PgValue) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"add" => Ok(StockAction::Add),
+            b"remove" => Ok(StockAction::Remove),
+            b"open" => Ok(StockAction::Open),
+            b"finish" => Ok(StockAction::Finish),
+            _ => Err(format!(
+                "Unrecognized enum variant: {:?}",
+                String::from_utf8_lossy(bytes.as_bytes())
+            )
+            .into()),
+        }
+    }
+}
+
 define_sql_function!(fn lower(x: Text) -> Text);
+define_sql_function!(#[sql_name = "lower"] fn lower_nullable(x: Nullable<Text>) -> Nullable<Text>);
+// provided by the pg_trgm extension
+define_sql_function!(fn similarity(a: Text, b: Text) -> Float4);
@@ -0,0 +1,187 @@
+use anyhow::Result;
+use openfoodfacts::{self as off, Output};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::mpsc;
+use std::thread::{self, sleep};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::config::config;
+use crate::db::{ean_variants, query_off_cache, store_off_cache};
+use crate::models::ItemDetails;
+
+/// Products that openfoodfacts gets wrong. A missing name means that its data is ignored
+/// and the product is treated as unknown.
+const DEFAULT_OVERRIDES: &[(&str, Option<&str>)] = &[
+    // wrong data in off, it's aldi kleenex and not bread...
+    ("4061463732958", None),
+];
+
+#[derive(Debug)]
+pub struct ProductInfo {
+    pub name: String,
+    pub details: ItemDetails,
+}
+
+/// Look up a product, trying all spellings of its EAN. The first one that is found wins.
+pub fn lookup(ean: &str) -> Result<Option<ProductInfo>> {
+    let variants = ean_variants(ean);
+    if let Some(name) = variants.iter().find_map(|ean| query_override(ean)) {
+        return Ok(name.map(|name| ProductInfo {
+            name,
+            details: ItemDetails::default(),
+        }));
+    }
+    for ean in &variants {
+        if let Some(product) = lookup_variant(ean)? {
+            return Ok(Some(product));
+        }
+    }
+    Ok(None)
+}
+
+fn lookup_variant(ean: &str) -> Result<Option<ProductInfo>> {
+    if let Some(cached) = query_off_cache(ean, config().off_cache_ttl)? {
+        return Ok(cached.name.map(|name| ProductInfo {
+            name,
+            details: ItemDetails {
+                brand: cached.brand,
+                quantity: cached.quantity,
+                category: cached.category,
+            },
+        }));
+    }
+    let product = lookup_off_with_retries(ean)?;
+    match &product {
+        Some(product) => store_off_cache(ean, Some(&product.name), &product.details, None)?,
+        None => store_off_cache(ean, None, &ItemDetails::default(), None)?,
+    }
+    Ok(product)
+}
+
+/// Get the overridden name of a product. The override file is read every time, so it can be
+/// edited without restarting.
+fn query_override(ean: &str) -> Option<Option<String>> {
+    if let Some(path) = &config().name_overrides {
+        let overrides = fs::read_to_string(path)
+            .map_err(|err| anyhow::anyhow!("{err}"))
+            .and_then(|data| {
+                serde_json::from_str::<HashMap<String, Option<String>>>(&data)
+                    .map_err(|err| anyhow::anyhow!("{err}"))
+            });
+        match overrides {
+            Ok(mut overrides) => {
+                if let Some(name) = overrides.remove(ean) {
+                    return Some(name);
+                }
+            }
+            Err(err) => warn!("Could not read name overrides {}: {err}", path.display()),
+        }
+    }
+    DEFAULT_OVERRIDES
+        .iter()
+        .find(|(code, _)| *code == ean)
+        .map(|(_, name)| name.map(String::from))
+}
+
+fn lookup_off_with_retries(ean: &str) -> Result<Option<ProductInfo>> {
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        match lookup_off_with_timeout(ean, config().off_timeout) {
+            Ok(product) => return Ok(product),
+            Err(err) if attempt < config().off_retries => {
+                warn!(
+                    "openfoodfacts lookup failed: {err}, retrying in {}s",
+                    delay.as_secs()
+                );
+                sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Run the lookup in a separate thread, since the client does not let us set a timeout. A
+/// request that takes too long is abandoned, and its thread ends whenever it completes.
+fn lookup_off_with_timeout(ean: &str, timeout: Duration) -> Result<Option<ProductInfo>> {
+    let (tx, rx) = mpsc::channel();
+    let ean = ean.to_string();
+    thread::spawn(move || {
+        // the receiver is gone if we gave up waiting, so there is nobody to tell
+        let _ = tx.send(lookup_off(&ean));
+    });
+    rx.recv_timeout(timeout).map_err(|_| {
+        anyhow::anyhow!(
+            "No response from openfoodfacts within {}s",
+            timeout.as_secs()
+        )
+    })?
+}
+
+fn lookup_off(ean: &str) -> Result<Option<ProductInfo>> {
+    if config().off_use_v2 {
+        // v0 may still know the product, e.g. when v2 has it without a name
+        match lookup_off_v2(ean) {
+            Ok(Some(product)) => return Ok(Some(product)),
+            Ok(None) => {}
+            Err(err) => warn!("openfoodfacts v2 lookup failed, trying v0: {err}"),
+        }
+    }
+    lookup_off_v0(ean)
+}
+
+const FIELDS: &str = "product_name,product_name_de,brands,quantity,categories";
+
+fn lookup_off_v0(ean: &str) -> Result<Option<ProductInfo>> {
+    let client = off::v0().build().unwrap();
+    let response = client
+        .product(ean, Some(Output::new().fields(FIELDS)))
+        .map_err(|err| anyhow::anyhow!("Could not load product: {err}"))?;
+    parse_product(json!(response.json::<HashMap::<String, Value>>()?))
+}
+
+fn lookup_off_v2(ean: &str) -> Result<Option<ProductInfo>> {
+    let client = off::v2().build().unwrap();
+    let response = client
+        .product(ean, Some(Output::new().fields(FIELDS)))
+        .map_err(|err| anyhow::anyhow!("Could not load product: {err}"))?;
+    parse_product(json!(response.json::<HashMap::<String, Value>>()?))
+}
+
+fn parse_product(data: Value) -> Result<Option<ProductInfo>> {
+    // v0 and v2 both use 1 for found products, newer API versions use "success"
+    let found = data["status"].as_i64() == Some(1) || data["status"].as_str() == Some("success");
+    if !found {
+        return Ok(None);
+    }
+    let product = &data["product"];
+    let name = product["product_name_de"]
+        .as_str()
+        .filter(|n| !n.is_empty())
+        .or(product["product_name"].as_str())
+        .ok_or(anyhow::anyhow!("Product has no name"))?;
+    Ok(Some(ProductInfo {
+        name: name.into(),
+        details: ItemDetails {
+            brand: first_entry(&product["brands"]),
+            quantity: first_entry(&product["quantity"]),
+            category: first_entry(&product["categories"]),
+        },
+    }))
+}
+
+/// Get the first entry of a comma-separated list, which is what openfoodfacts uses for
+/// fields that can have more than one value.
+fn first_entry(value: &Value) -> Option<String> {
+    value
+        .as_str()?
+        .split(',')
+        .map(str::trim)
+        .find(|s| !s.is_empty())
+        .map(String::from)
+}
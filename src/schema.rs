@@ -4,12 +4,16 @@ pub mod sql_types {
     #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "item_kind"))]
     pub struct ItemKind;
+
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "stock_action"))]
+    pub struct StockAction;
 }
 
 diesel::table! {
-    aliases (ean) {
+    item_eans (ean) {
         ean -> Varchar,
-        alias_for -> Varchar,
+        item_id -> Int4,
     }
 }
 
@@ -22,6 +26,24 @@ diesel::table! {
         name -> Varchar,
         kind -> ItemKind,
         ean -> Nullable<Varchar>,
+        min_stock -> Nullable<Int4>,
+        brand -> Nullable<Varchar>,
+        quantity -> Nullable<Varchar>,
+        category -> Nullable<Varchar>,
+        deleted_dt -> Nullable<Timestamptz>,
+        open_shelf_days -> Nullable<Int4>,
+        note -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    off_cache (ean) {
+        ean -> Varchar,
+        name -> Nullable<Varchar>,
+        fetched_dt -> Timestamptz,
+        brand -> Nullable<Varchar>,
+        quantity -> Nullable<Varchar>,
+        category -> Nullable<Varchar>,
     }
 }
 
@@ -32,9 +54,44 @@ diesel::table! {
         added_dt -> Timestamptz,
         opened_dt -> Nullable<Timestamptz>,
         removed_dt -> Nullable<Timestamptz>,
+        expiry_dt -> Nullable<Date>,
+        quantity -> Int4,
+        location -> Nullable<Varchar>,
+        remaining_fraction -> Nullable<Float4>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::StockAction;
+
+    stock_log (id) {
+        id -> Int4,
+        stock_id -> Int4,
+        action -> StockAction,
+        occurred_dt -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    shopping_list (id) {
+        id -> Int4,
+        item_id -> Int4,
+        added_dt -> Timestamptz,
+        removed_dt -> Nullable<Timestamptz>,
     }
 }
 
+diesel::joinable!(item_eans -> items (item_id));
+diesel::joinable!(shopping_list -> items (item_id));
 diesel::joinable!(stock -> items (item_id));
+diesel::joinable!(stock_log -> stock (stock_id));
 
-diesel::allow_tables_to_appear_in_same_query!(aliases, items, stock,);
+diesel::allow_tables_to_appear_in_same_query!(
+    item_eans,
+    items,
+    off_cache,
+    shopping_list,
+    stock,
+    stock_log,
+);
@@ -0,0 +1,178 @@
+use anyhow::Result;
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+use tracing::{error, info};
+
+use crate::EXPIRY_WARNING_DAYS;
+use crate::config::config;
+use crate::db::{
+    ItemOrder, query_all_items, query_expiring_soon, query_item_by_id, query_shopping_list,
+    query_stock_entries, query_stock_history, remove_from_shopping_list,
+};
+use crate::metrics;
+use crate::models::{Item, ShoppingListEntry, Stock, StockLogEntry};
+
+enum ApiError {
+    NotFound,
+    BadRequest(&'static str),
+    Internal(anyhow::Error),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            ApiError::NotFound => StatusCode::NOT_FOUND.into_response(),
+            ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg).into_response(),
+            ApiError::Internal(err) => {
+                error!("web request failed: {err}");
+                (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ItemStock {
+    item: Item,
+    stock: Stock,
+}
+
+#[derive(Serialize)]
+struct ShoppingListItem {
+    item: Item,
+    entry: ShoppingListEntry,
+}
+
+/// How many items are returned when no limit is given.
+const DEFAULT_PAGE_SIZE: i64 = 100;
+
+#[derive(Deserialize)]
+struct ItemsParams {
+    category: Option<String>,
+    limit: Option<i64>,
+    #[serde(default)]
+    offset: i64,
+    #[serde(default)]
+    order: ItemOrder,
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+#[derive(Deserialize)]
+struct ExpiringParams {
+    days: Option<i64>,
+}
+
+/// Run the web API. This blocks, so it should be called in a separate thread.
+pub fn serve(port: u16) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let mut app = Router::new()
+            .route("/items", get(items))
+            .route("/items/{id}/stock", get(item_stock))
+            .route("/items/{id}/history", get(item_history))
+            .route("/stock/expiring", get(expiring_stock))
+            .route("/shopping-list", get(shopping_list));
+        if config().web_allow_changes {
+            app = app.route("/shopping-list/{id}", delete(remove_shopping_list_item));
+        }
+        if config().metrics {
+            app = app.route("/metrics", get(prometheus_metrics));
+        }
+        let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+        info!("web api listening on port {port}");
+        axum::serve(listener, app).await?;
+        Ok::<_, anyhow::Error>(())
+    })
+}
+
+/// Run a (blocking) database query without blocking the async runtime.
+async fn blocking<T, F>(f: F) -> Result<T, ApiError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    spawn_blocking(f)
+        .await
+        .map_err(|err| ApiError::Internal(err.into()))?
+        .map_err(ApiError::Internal)
+}
+
+async fn prometheus_metrics() -> Result<String, ApiError> {
+    blocking(metrics::gather).await
+}
+
+async fn items(Query(params): Query<ItemsParams>) -> Result<Json<Vec<Item>>, ApiError> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit < 0 || params.offset < 0 {
+        return Err(ApiError::BadRequest(
+            "limit and offset must not be negative",
+        ));
+    }
+    blocking(move || {
+        query_all_items(
+            params.category.as_deref(),
+            limit,
+            params.offset,
+            params.order,
+            params.include_deleted,
+        )
+    })
+    .await
+    .map(Json)
+}
+
+async fn item_stock(Path(id): Path<i32>) -> Result<Json<Vec<Stock>>, ApiError> {
+    let entries = blocking(move || match query_item_by_id(id)? {
+        Some(item) => query_stock_entries(&item).map(Some),
+        None => Ok(None),
+    })
+    .await?;
+    entries.map(Json).ok_or(ApiError::NotFound)
+}
+
+async fn item_history(Path(id): Path<i32>) -> Result<Json<Vec<StockLogEntry>>, ApiError> {
+    let history = blocking(move || match query_item_by_id(id)? {
+        Some(item) => query_stock_history(item.id).map(Some),
+        None => Ok(None),
+    })
+    .await?;
+    history.map(Json).ok_or(ApiError::NotFound)
+}
+
+async fn expiring_stock(
+    Query(params): Query<ExpiringParams>,
+) -> Result<Json<Vec<ItemStock>>, ApiError> {
+    let days = params.days.unwrap_or(EXPIRY_WARNING_DAYS);
+    let expiring = blocking(move || query_expiring_soon(days)).await?;
+    Ok(Json(
+        expiring
+            .into_iter()
+            .map(|(item, stock)| ItemStock { item, stock })
+            .collect(),
+    ))
+}
+
+async fn shopping_list() -> Result<Json<Vec<ShoppingListItem>>, ApiError> {
+    let list = blocking(query_shopping_list).await?;
+    Ok(Json(
+        list.into_iter()
+            .map(|(entry, item)| ShoppingListItem { item, entry })
+            .collect(),
+    ))
+}
+
+async fn remove_shopping_list_item(Path(id): Path<i32>) -> Result<StatusCode, ApiError> {
+    if blocking(move || remove_from_shopping_list(id, None)).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
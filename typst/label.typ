@@ -21,7 +21,7 @@
   text(a, it)
 })
 
-#let label(width: length, height: length, date: text, name: text, code: bytes) = {
+#let label(width: length, height: length, date: text, name: text, code: bytes, ean: none) = {
   set page(width: width, height: height, margin: 0pt)
   set text(font: "Liberation Sans")
 
@@ -46,6 +46,9 @@
       [
         #place(horizon + left, image(code, height: 100%))
         #place(horizon + right, block(height: 75%, fill-height-with-text(date)))
+        #if ean != none {
+          place(bottom + center, text(size: height * 8%, ean))
+        }
       ],
     ))
   ]
@@ -57,4 +60,5 @@
   name: sys.inputs.at("name", default: "Schupfnudel-Wirsing-Auflauf mit Kassler"),
   date: sys.inputs.at("date", default: "12/25"),
   code: sys.inputs.at("code", default: none),
+  ean: sys.inputs.at("ean", default: none),
 )
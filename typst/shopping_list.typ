@@ -0,0 +1,13 @@
+#let shopping-list(width: length, items: array) = {
+  set page(width: width, height: auto, margin: 8pt)
+  set text(font: "Liberation Sans", size: 24pt)
+
+  for item in items [
+    - #item
+  ]
+}
+
+#shopping-list(
+  width: sys.inputs.at("width", default: 696) * 1pt,
+  items: sys.inputs.at("items", default: ("Milch", "Butter", "Schupfnudeln")),
+)